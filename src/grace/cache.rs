@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use super::git::{self, Credentials, GitError};
+
+// Environment variable that moves the shared cache, e.g. for CI runners
+pub const GRACE_GIT_CACHE_ENV: &str = "GRACE_GIT_CACHE";
+
+/// Returns the folder that holds the shared package repositories. It is
+/// shared by all projects of the user, so each package is only downloaded
+/// once.
+pub fn cache_root() -> PathBuf {
+    if let Some(dir) = std::env::var_os(GRACE_GIT_CACHE_ENV) {
+        return PathBuf::from(dir);
+    }
+
+    let mut root = dirs::cache_dir().expect("Cannot determine the cache directory of the user");
+    root.push("grace");
+    root.push("git");
+    root
+}
+
+/// Returns a short, stable hex hash of data that is safe to use in paths
+pub(crate) fn short_hash(data: &str) -> String {
+    let hash = Sha256::digest(data.as_bytes());
+    hash[..8].iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Returns the folder of the bare repository that caches the package at uri
+pub fn repository_dir(uri: &str) -> PathBuf {
+    let mut dir = cache_root();
+    dir.push(short_hash(uri));
+    dir
+}
+
+/// Returns a client for the cached repository of the package at uri,
+/// creating the repository if there is none yet
+pub fn open(uri: &str, credentials: Option<Credentials>) -> Result<git::GitClient, GitError> {
+    let dir = repository_dir(uri);
+    let git = git::GitClient::create()
+        .cwd(dir.to_str().unwrap().to_string())
+        .silent()
+        .credentials(credentials);
+
+    if !dir.exists() {
+        std::fs::create_dir_all(dir.clone()).map_err(|e| GitError {
+            subcommand: "init".to_string(),
+            status: None,
+            stderr: e.to_string(),
+            cwd: dir.to_str().unwrap().to_string(),
+            auth_failure: false,
+        })?;
+
+        let created = git.init(true).and_then(|_| git.remote(uri.to_string()));
+        if created.is_err() {
+            // don't leave a half set up cache behind
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        created?;
+    }
+    Ok(git)
+}
+
+/// Returns a client for the cached repository of the package at uri if it
+/// exists
+pub fn find(uri: &str, credentials: Option<Credentials>) -> Option<git::GitClient> {
+    let dir = repository_dir(uri);
+    if !dir.exists() {
+        return None;
+    }
+
+    Some(
+        git::GitClient::create()
+            .cwd(dir.to_str().unwrap().to_string())
+            .silent()
+            .credentials(credentials),
+    )
+}
+
+/// Returns the name of the worktree for a checkout at path. The name is
+/// derived from the absolute path, so projects never share a worktree.
+pub fn worktree_name(path: &Path) -> String {
+    let path = std::path::absolute(path).unwrap_or(path.to_path_buf());
+    format!("grace-{}", short_hash(path.to_str().unwrap()))
+}
+
+/// Checks if the checkout at path is a worktree that lost its entry in the
+/// shared cache or whose entry now belongs to another checkout. Git commands
+/// in such a checkout fail or act on the other checkout's HEAD. A checkout
+/// that was moved along with its project is linked to its entry again.
+pub fn is_stale_worktree(path: &Path) -> bool {
+    let git_file = path.join(".git");
+    // Standalone clones don't depend on the cache
+    if !git_file.is_file() {
+        return false;
+    }
+    let Some(entry) = worktree_entry(&git_file) else {
+        return true;
+    };
+    if !entry.is_dir() {
+        return true;
+    }
+
+    // The entry points back to the .git file of the checkout using it
+    let back_link = entry.join("gitdir");
+    let linked = std::fs::read_to_string(&back_link)
+        .ok()
+        .map(|x| PathBuf::from(x.trim()));
+    let canonical = |x: &Path| std::fs::canonicalize(x).ok();
+    match linked {
+        Some(linked) if canonical(&linked) == canonical(&git_file) => false,
+        Some(linked)
+            if worktree_entry(&linked).and_then(|x| canonical(&x)) == canonical(&entry) =>
+        {
+            true
+        }
+        _ => {
+            let git_file = std::path::absolute(&git_file).unwrap_or(git_file);
+            std::fs::write(back_link, format!("{}\n", git_file.display())).is_err()
+        }
+    }
+}
+
+/// Returns the worktree entry a .git file points to
+fn worktree_entry(git_file: &Path) -> Option<PathBuf> {
+    let content = std::fs::read_to_string(git_file).ok()?;
+    let entry = content.trim().strip_prefix("gitdir:")?.trim();
+    Some(git_file.parent()?.join(entry))
+}
@@ -0,0 +1,430 @@
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Output},
+};
+
+use super::{Credentials, GitBackend, GitError, NOT_FAST_FORWARD};
+
+// Credential helper that hands the token to git. The token is passed in the
+// environment, so it never shows up on a command line.
+const TOKEN_HELPER: &str = "!f() { test \"$1\" = get && echo \"username=$GRACE_GIT_USERNAME\" && echo \"password=$GRACE_GIT_TOKEN\"; }; f";
+
+// Messages git and ssh print if the remote did not accept the credentials
+const AUTH_FAILURES: [&str; 7] = [
+    "Authentication failed",
+    "Permission denied (publickey",
+    "could not read Username",
+    "could not read Password",
+    "terminal prompts disabled",
+    "Host key verification failed",
+    "The requested URL returned error: 401",
+];
+
+/// Runs the git executable found on PATH
+pub struct CliBackend {
+    cwd: String,
+    silent: bool,
+    credentials: Option<Credentials>,
+}
+
+impl CliBackend {
+    pub fn new(cwd: String, silent: bool, credentials: Option<Credentials>) -> Self {
+        Self {
+            cwd,
+            silent,
+            credentials,
+        }
+    }
+
+    /// Runs git with the given arguments. Unless the backend is silent, the
+    /// output of git is forwarded.
+    fn run(&self, args: Vec<String>) -> Result<Output, GitError> {
+        let o = self.execute(args)?;
+        if !self.silent {
+            std::io::stdout().write_all(&o.stdout).unwrap();
+            std::io::stderr().write_all(&o.stderr).unwrap();
+        }
+        Ok(o)
+    }
+
+    /// Runs git with the given arguments and returns its trimmed output
+    fn query(&self, args: Vec<String>) -> Result<String, GitError> {
+        let o = self.execute(args)?;
+        Ok(String::from_utf8_lossy(&o.stdout).trim().to_string())
+    }
+
+    fn execute(&self, args: Vec<String>) -> Result<Output, GitError> {
+        let subcommand = args.first().cloned().unwrap_or_default();
+        let mut command = Command::new("git");
+        command.current_dir(self.cwd.clone()).args(args);
+        if let Some(credentials) = self.credentials.as_ref() {
+            self.apply_credentials(&mut command, credentials)
+                .map_err(|e| GitError {
+                    subcommand: subcommand.clone(),
+                    status: None,
+                    stderr: e,
+                    cwd: self.cwd.clone(),
+                    auth_failure: true,
+                })?;
+        }
+
+        let o = command.output().map_err(|e| GitError {
+            subcommand: subcommand.clone(),
+            status: None,
+            stderr: e.to_string(),
+            cwd: self.cwd.clone(),
+            auth_failure: false,
+        })?;
+
+        if !o.status.success() {
+            let stderr = String::from_utf8_lossy(&o.stderr).to_string();
+            return Err(GitError {
+                subcommand,
+                status: o.status.code(),
+                auth_failure: AUTH_FAILURES.iter().any(|x| stderr.contains(x)),
+                stderr,
+                cwd: self.cwd.clone(),
+            });
+        }
+        Ok(o)
+    }
+
+    /// Passes the credentials to git through its environment
+    fn apply_credentials(
+        &self,
+        command: &mut Command,
+        credentials: &Credentials,
+    ) -> Result<(), String> {
+        // never hang waiting for input that cannot be given
+        command.env("GIT_TERMINAL_PROMPT", "0");
+
+        if let Some(key) = credentials.ssh_key_path() {
+            command.env(
+                "GIT_SSH_COMMAND",
+                format!(
+                    "ssh -i '{}' -o IdentitiesOnly=yes",
+                    key.replace('\'', "'\\''")
+                ),
+            );
+        }
+
+        if let Some(askpass) = credentials.askpass.as_ref() {
+            command.env("GIT_ASKPASS", askpass);
+        }
+
+        if let Some(token) = credentials.token() {
+            // An empty helper drops the helpers of the user's configuration,
+            // so they can't answer with other credentials first
+            let count: usize = std::env::var("GIT_CONFIG_COUNT")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0);
+            command
+                .env("GIT_CONFIG_COUNT", (count + 2).to_string())
+                .env(format!("GIT_CONFIG_KEY_{}", count), "credential.helper")
+                .env(format!("GIT_CONFIG_VALUE_{}", count), "")
+                .env(format!("GIT_CONFIG_KEY_{}", count + 1), "credential.helper")
+                .env(format!("GIT_CONFIG_VALUE_{}", count + 1), TOKEN_HELPER)
+                .env("GRACE_GIT_USERNAME", credentials.username())
+                .env("GRACE_GIT_TOKEN", token?);
+        }
+        Ok(())
+    }
+}
+
+impl GitBackend for CliBackend {
+    fn fetch(&self) -> Result<(), GitError> {
+        self.run(vec!["fetch".to_string()])?;
+        Ok(())
+    }
+
+    fn init(&self, bare: bool) -> Result<(), GitError> {
+        let mut args = vec!["init".to_string()];
+        if bare {
+            args.push("--bare".to_string());
+        }
+
+        self.run(args)?;
+        Ok(())
+    }
+
+    fn worktree_add(
+        &self,
+        name: String,
+        path: String,
+        commit_hash: String,
+        checkout: bool,
+    ) -> Result<(), GitError> {
+        // A checkout that was deleted is still registered, only that entry is
+        // removed as other entries may belong to moved projects
+        if !Path::new(&path).exists() {
+            let _ = self.run(vec![
+                "worktree".to_string(),
+                "remove".to_string(),
+                "--force".to_string(),
+                path.clone(),
+            ]);
+        }
+
+        // git names worktrees after their folder, so the worktree is created
+        // next to its final location and moved there afterwards
+        let temp_path = Path::new(&path)
+            .with_file_name(name)
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let mut args = vec![
+            "worktree".to_string(),
+            "add".to_string(),
+            "--detach".to_string(),
+        ];
+        if !checkout {
+            args.push("--no-checkout".to_string());
+        }
+        args.push(temp_path.clone());
+        args.push(commit_hash);
+        self.run(args)?;
+
+        let moved = self.run(vec![
+            "worktree".to_string(),
+            "move".to_string(),
+            temp_path.clone(),
+            path,
+        ]);
+        if moved.is_err() {
+            let _ = self.run(vec![
+                "worktree".to_string(),
+                "remove".to_string(),
+                "--force".to_string(),
+                temp_path,
+            ]);
+        }
+        moved?;
+        Ok(())
+    }
+
+    fn sparse_checkout(&self, paths: Vec<String>) -> Result<(), GitError> {
+        // Cone mode would check out the files of the root folder as well, the
+        // patterns are anchored so only the given paths match
+        let args = if paths.is_empty() {
+            vec!["sparse-checkout".to_string(), "disable".to_string()]
+        } else {
+            let mut args = vec![
+                "sparse-checkout".to_string(),
+                "set".to_string(),
+                "--no-cone".to_string(),
+            ];
+            args.extend(paths.iter().map(|x| format!("/{}", x.trim_matches('/'))));
+            args
+        };
+
+        self.run(args)?;
+        Ok(())
+    }
+
+    fn sparse_paths(&self) -> Result<Vec<String>, GitError> {
+        let paths = match self.query(vec!["sparse-checkout".to_string(), "list".to_string()]) {
+            Ok(paths) => paths,
+            Err(e) if e.stderr.contains("not sparse") => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let cone = self
+            .query(vec![
+                "config".to_string(),
+                "--get".to_string(),
+                "core.sparseCheckoutCone".to_string(),
+            ])
+            .is_ok_and(|x| x == "true");
+
+        let mut result: Vec<String> = paths
+            .lines()
+            .map(|x| x.strip_prefix('/').unwrap_or(x).to_string())
+            .collect();
+        if cone {
+            result.insert(0, "/".to_string());
+        }
+        Ok(result)
+    }
+
+    fn remote(&self, remote: String) -> Result<(), GitError> {
+        self.run(vec![
+            "remote".to_string(),
+            "add".to_string(),
+            "origin".to_string(),
+            remote,
+        ])?;
+        Ok(())
+    }
+
+    fn fetch_commit(&self, commit_hash: String, depth: Option<u32>) -> Result<(), GitError> {
+        let mut args = vec!["fetch".to_string()];
+        if let Some(depth) = depth {
+            args.push(format!("--depth={}", depth));
+        }
+        args.push("origin".to_string());
+        args.push(commit_hash);
+
+        self.run(args)?;
+        Ok(())
+    }
+
+    fn default_branch(&self) -> Result<String, GitError> {
+        let output = self.query(vec![
+            "ls-remote".to_string(),
+            "--symref".to_string(),
+            "origin".to_string(),
+            "HEAD".to_string(),
+        ])?;
+
+        // The symref is reported as "ref: refs/heads/<branch>", followed by a tab
+        // and "HEAD"
+        output
+            .lines()
+            .filter_map(|line| line.strip_prefix("ref: "))
+            .filter_map(|line| line.split('\t').next())
+            .find_map(|r| r.strip_prefix("refs/heads/"))
+            .map(|branch| branch.to_string())
+            .ok_or_else(|| GitError {
+                subcommand: "ls-remote".to_string(),
+                status: None,
+                stderr: "origin does not report a default branch".to_string(),
+                cwd: self.cwd.clone(),
+                auth_failure: false,
+            })
+    }
+
+    fn clone(&self, uri: String, bare: bool, branch: Option<String>) -> Result<(), GitError> {
+        let mut args = vec!["clone".to_string()];
+        if let Some(branch) = branch {
+            args.push("--branch".to_string());
+            args.push(branch);
+        }
+        args.push(uri);
+        if bare {
+            args.push(".".to_string());
+        }
+
+        self.run(args)?;
+        Ok(())
+    }
+
+    fn pull(&self, branch: String) -> Result<(), GitError> {
+        self.run(vec!["pull".to_string(), "origin".to_string(), branch])?;
+        Ok(())
+    }
+
+    fn checkout(&self, commit_hash: String) -> Result<(), GitError> {
+        self.run(vec!["checkout".to_string(), commit_hash])?;
+        Ok(())
+    }
+
+    fn submodule_update(&self) -> Result<(), GitError> {
+        self.run(vec![
+            "submodule".to_string(),
+            "update".to_string(),
+            "--init".to_string(),
+            "--recursive".to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn lfs_pull(&self) -> Result<(), GitError> {
+        match self.run(vec!["lfs".to_string(), "pull".to_string()]) {
+            Ok(_) => Ok(()),
+            Err(mut e) if e.stderr.contains("is not a git command") => {
+                e.stderr = "Git LFS is not installed, see https://git-lfs.com".to_string();
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add(&self, path: String) -> Result<(), GitError> {
+        self.run(vec!["add".to_string(), path])?;
+        Ok(())
+    }
+
+    fn commit(&self, message: String) -> Result<(), GitError> {
+        self.run(vec!["commit".to_string(), "-m".to_string(), message])?;
+        Ok(())
+    }
+
+    fn push(&self, branch: String) -> Result<bool, GitError> {
+        let pushed = self.run(vec![
+            "push".to_string(),
+            "origin".to_string(),
+            format!("HEAD:refs/heads/{}", branch),
+        ]);
+        match pushed {
+            Ok(_) => Ok(true),
+            Err(e) if NOT_FAST_FORWARD.iter().any(|x| e.stderr.contains(x)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn reset(&self, revision: String) -> Result<(), GitError> {
+        self.run(vec!["reset".to_string(), "--hard".to_string(), revision])?;
+        Ok(())
+    }
+
+    fn rev_parse(&self, revision: String) -> Result<String, GitError> {
+        self.query(vec!["rev-parse".to_string(), revision])
+    }
+
+    fn remote_url(&self) -> Result<String, GitError> {
+        self.query(vec![
+            "remote".to_string(),
+            "get-url".to_string(),
+            "origin".to_string(),
+        ])
+    }
+
+    fn remote_contains(&self, commit_hash: String) -> Result<bool, GitError> {
+        self.run(vec![
+            "fetch".to_string(),
+            "--tags".to_string(),
+            "origin".to_string(),
+            "+refs/heads/*:refs/remotes/origin/*".to_string(),
+        ])?;
+        let refs = self.query(vec![
+            "for-each-ref".to_string(),
+            format!("--contains={}", commit_hash),
+            "--format=%(refname)".to_string(),
+            "refs/remotes/origin".to_string(),
+            "refs/tags".to_string(),
+        ])?;
+        Ok(!refs.is_empty())
+    }
+
+    fn has_commit(&self, commit_hash: String) -> bool {
+        self.query(vec![
+            "cat-file".to_string(),
+            "-e".to_string(),
+            format!("{}^{{commit}}", commit_hash),
+        ])
+        .is_ok()
+    }
+
+    fn show(&self, commit_hash: String, file: String) -> Result<Option<String>, GitError> {
+        // ls-tree fails if the commit cannot be read, but not if the file is
+        // missing
+        let listed = self.query(vec![
+            "ls-tree".to_string(),
+            "--name-only".to_string(),
+            commit_hash.clone(),
+            "--".to_string(),
+            file.clone(),
+        ])?;
+        if listed.is_empty() {
+            return Ok(None);
+        }
+
+        self.query(vec![
+            "show".to_string(),
+            format!("{}:{}", commit_hash, file),
+        ])
+        .map(Some)
+    }
+}
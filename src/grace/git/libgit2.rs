@@ -0,0 +1,516 @@
+use std::{path::Path, process::Command};
+
+use git2::{
+    build::{CheckoutBuilder, RepoBuilder},
+    Cred, CredentialType, Direction, ErrorClass, ErrorCode, FetchOptions, IndexAddOption,
+    PushOptions, RemoteCallbacks, Repository, ResetType, Signature, SubmoduleUpdateOptions,
+    WorktreeAddOptions,
+};
+
+use super::{Credentials, GitBackend, GitError, NOT_FAST_FORWARD};
+
+// Number of times libgit2 may ask for credentials before giving up, it keeps
+// asking as long as credentials are handed out
+const MAX_CREDENTIAL_REQUESTS: u32 = 4;
+
+/// Runs all operations in process using libgit2
+pub struct Git2Backend {
+    cwd: String,
+    credentials: Option<Credentials>,
+}
+
+impl Git2Backend {
+    pub fn new(cwd: String, credentials: Option<Credentials>) -> Self {
+        Self { cwd, credentials }
+    }
+
+    fn error(&self, subcommand: &str, e: git2::Error) -> GitError {
+        GitError {
+            subcommand: subcommand.to_string(),
+            status: None,
+            // errors raised in callbacks come back with their class appended
+            stderr: e.message().split("; class=").next().unwrap().to_string(),
+            cwd: self.cwd.clone(),
+            auth_failure: e.code() == ErrorCode::Auth || e.class() == ErrorClass::Ssh,
+        }
+    }
+
+    /// Returns callbacks that answer libgit2's requests for credentials
+    fn callbacks(&self) -> RemoteCallbacks<'_> {
+        let mut callbacks = RemoteCallbacks::new();
+        let mut requests = 0;
+        callbacks.credentials(move |url, username, allowed| {
+            requests += 1;
+            if requests > MAX_CREDENTIAL_REQUESTS {
+                return Err(auth_error("the remote did not accept any credentials"));
+            }
+            self.credential(url, username, allowed)
+        });
+        callbacks
+    }
+
+    fn credential(
+        &self,
+        url: &str,
+        username: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        let credentials = self.credentials.clone().unwrap_or_default();
+        let user = username.unwrap_or("git");
+
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(user);
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            return match credentials.ssh_key_path() {
+                Some(key) => Cred::ssh_key(user, None, Path::new(&key), None),
+                None => Cred::ssh_key_from_agent(user),
+            };
+        }
+
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(token) = credentials.token() {
+                let token = token.map_err(|e| auth_error(&e))?;
+                return Cred::userpass_plaintext(&credentials.username(), &token);
+            }
+            if let Some(askpass) = credentials.askpass.as_ref() {
+                let user = ask(askpass, &format!("Username for '{}': ", url))?;
+                let password = ask(askpass, &format!("Password for '{}': ", url))?;
+                return Cred::userpass_plaintext(&user, &password);
+            }
+            let config = git2::Config::open_default()?;
+            return Cred::credential_helper(&config, url, username);
+        }
+
+        Cred::default()
+    }
+
+    fn open(&self, subcommand: &str) -> Result<Repository, GitError> {
+        Repository::open(&self.cwd).map_err(|e| self.error(subcommand, e))
+    }
+
+    /// Fetches the given refspecs from origin, the default refspecs are used
+    /// if none are given
+    fn fetch_refspecs(
+        &self,
+        repo: &Repository,
+        subcommand: &str,
+        refspecs: &[&str],
+    ) -> Result<(), GitError> {
+        let mut remote = repo
+            .find_remote("origin")
+            .map_err(|e| self.error(subcommand, e))?;
+        let mut options = FetchOptions::new();
+        options
+            .download_tags(git2::AutotagOption::All)
+            .remote_callbacks(self.callbacks());
+        remote
+            .fetch(refspecs, Some(&mut options), None)
+            .map_err(|e| self.error(subcommand, e))
+    }
+
+    fn update_submodules(&self, repo: &Repository) -> Result<(), GitError> {
+        let submodules = repo.submodules().map_err(|e| self.error("submodule", e))?;
+        for mut submodule in submodules {
+            let mut fetch = FetchOptions::new();
+            fetch.remote_callbacks(self.callbacks());
+            let mut options = SubmoduleUpdateOptions::new();
+            options.fetch(fetch);
+            submodule
+                .update(true, Some(&mut options))
+                .map_err(|e| self.error("submodule", e))?;
+
+            let nested = submodule.open().map_err(|e| self.error("submodule", e))?;
+            self.update_submodules(&nested)?;
+        }
+        Ok(())
+    }
+
+    fn find_commit<'r>(
+        &self,
+        repo: &'r Repository,
+        subcommand: &str,
+        revision: &str,
+    ) -> Result<git2::Commit<'r>, GitError> {
+        repo.revparse_single(revision)
+            .and_then(|o| o.peel_to_commit())
+            .map_err(|e| self.error(subcommand, e))
+    }
+}
+
+impl GitBackend for Git2Backend {
+    fn fetch(&self) -> Result<(), GitError> {
+        let repo = self.open("fetch")?;
+        self.fetch_refspecs(&repo, "fetch", &[])
+    }
+
+    // libgit2 has no support for shallow repositories, so the depth is
+    // ignored and the commit is fetched along with its history
+    fn fetch_commit(&self, commit_hash: String, _depth: Option<u32>) -> Result<(), GitError> {
+        let repo = self.open("fetch")?;
+        self.fetch_refspecs(&repo, "fetch", &[&commit_hash])
+    }
+
+    fn default_branch(&self) -> Result<String, GitError> {
+        let repo = self.open("ls-remote")?;
+        let mut remote = repo
+            .find_remote("origin")
+            .map_err(|e| self.error("ls-remote", e))?;
+        let connection = remote
+            .connect_auth(Direction::Fetch, Some(self.callbacks()), None)
+            .map_err(|e| self.error("ls-remote", e))?;
+        let head = connection
+            .default_branch()
+            .map_err(|e| self.error("ls-remote", e))?;
+        let head = head.as_str().unwrap_or_default();
+        Ok(head.strip_prefix("refs/heads/").unwrap_or(head).to_string())
+    }
+
+    fn init(&self, bare: bool) -> Result<(), GitError> {
+        let created = if bare {
+            Repository::init_bare(&self.cwd)
+        } else {
+            Repository::init(&self.cwd)
+        };
+        created.map_err(|e| self.error("init", e))?;
+        Ok(())
+    }
+
+    // libgit2 always checks out new worktrees
+    fn worktree_add(
+        &self,
+        name: String,
+        path: String,
+        commit_hash: String,
+        _checkout: bool,
+    ) -> Result<(), GitError> {
+        let repo = self.open("worktree")?;
+        let commit = self.find_commit(&repo, "worktree", &commit_hash)?;
+
+        // Only a worktree of the same name whose folder is gone is pruned,
+        // other worktrees may belong to projects that were moved
+        if let Ok(worktree) = repo.find_worktree(&name) {
+            if worktree.is_prunable(None).unwrap_or(false) {
+                let _ = worktree.prune(None);
+            }
+        }
+
+        // Like git, make the name unique
+        let base = name.clone();
+        let mut name = base.clone();
+        let mut suffix = 1;
+        while repo.find_worktree(&name).is_ok() {
+            name = format!("{}{}", base, suffix);
+            suffix += 1;
+        }
+
+        // libgit2 can only add worktrees for branches, so a temporary branch
+        // is created and dropped once the worktree's HEAD is detached
+        let mut branch = repo
+            .branch(&format!("grace-worktree-{}", name), &commit, true)
+            .map_err(|e| self.error("worktree", e))?;
+        let mut options = WorktreeAddOptions::new();
+        options.reference(Some(branch.get()));
+        let worktree = repo
+            .worktree(&name, Path::new(&path), Some(&options))
+            .map_err(|e| self.error("worktree", e))?;
+
+        let checkout = Repository::open_from_worktree(&worktree)
+            .and_then(|r| r.set_head_detached(commit.id()))
+            .and_then(|_| branch.delete());
+        checkout.map_err(|e| self.error("worktree", e))
+    }
+
+    fn remote(&self, remote: String) -> Result<(), GitError> {
+        let repo = self.open("remote")?;
+        repo.remote("origin", &remote)
+            .map_err(|e| self.error("remote", e))?;
+        Ok(())
+    }
+
+    fn clone(&self, uri: String, bare: bool, branch: Option<String>) -> Result<(), GitError> {
+        let target = if bare {
+            Path::new(&self.cwd).to_path_buf()
+        } else {
+            // like git, name the new folder after the last part of the URI
+            let name = uri
+                .trim_end_matches('/')
+                .rsplit(['/', '\\', ':'])
+                .next()
+                .unwrap_or_default()
+                .trim_end_matches(".git")
+                .to_string();
+            Path::new(&self.cwd).join(name)
+        };
+
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(self.callbacks());
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(options);
+        if let Some(branch) = branch.as_ref() {
+            builder.branch(branch);
+        }
+        builder
+            .clone(&uri, &target)
+            .map_err(|e| self.error("clone", e))?;
+        Ok(())
+    }
+
+    fn pull(&self, branch: String) -> Result<(), GitError> {
+        let repo = self.open("pull")?;
+        self.fetch_refspecs(
+            &repo,
+            "pull",
+            &[&format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch)],
+        )?;
+
+        let remote_ref = format!("refs/remotes/origin/{}", branch);
+        let target = self.find_commit(&repo, "pull", &remote_ref)?;
+
+        // a checked out package is on a detached HEAD, fetching is all there
+        // is to do in that case
+        if repo.head_detached().unwrap_or(false) {
+            return Ok(());
+        }
+
+        let local_ref = format!("refs/heads/{}", branch);
+        if let Ok(head) = self.find_commit(&repo, "pull", &local_ref) {
+            let fast_forward = head.id() == target.id()
+                || repo
+                    .graph_descendant_of(target.id(), head.id())
+                    .map_err(|e| self.error("pull", e))?;
+            if !fast_forward {
+                return Err(GitError {
+                    subcommand: "pull".to_string(),
+                    status: None,
+                    stderr: "local branch has diverged, only fast forwards are supported"
+                        .to_string(),
+                    cwd: self.cwd.clone(),
+                    auth_failure: false,
+                });
+            }
+        }
+
+        repo.reference(&local_ref, target.id(), true, "pull: fast-forward")
+            .map_err(|e| self.error("pull", e))?;
+        repo.set_head(&local_ref)
+            .map_err(|e| self.error("pull", e))?;
+        repo.checkout_head(Some(CheckoutBuilder::new().force()))
+            .map_err(|e| self.error("pull", e))
+    }
+
+    fn checkout(&self, commit_hash: String) -> Result<(), GitError> {
+        let repo = self.open("checkout")?;
+        let commit = self.find_commit(&repo, "checkout", &commit_hash)?;
+        repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))
+            .map_err(|e| self.error("checkout", e))?;
+        repo.set_head_detached(commit.id())
+            .map_err(|e| self.error("checkout", e))
+    }
+
+    fn submodule_update(&self) -> Result<(), GitError> {
+        let repo = self.open("submodule")?;
+        self.update_submodules(&repo)
+    }
+
+    fn sparse_checkout(&self, paths: Vec<String>) -> Result<(), GitError> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        Err(GitError {
+            subcommand: "sparse-checkout".to_string(),
+            status: None,
+            stderr:
+                "Sparse checkouts are not supported by the libgit2 backend, use --git-backend cli"
+                    .to_string(),
+            cwd: self.cwd.clone(),
+            auth_failure: false,
+        })
+    }
+
+    fn sparse_paths(&self) -> Result<Vec<String>, GitError> {
+        Ok(vec![])
+    }
+
+    fn lfs_pull(&self) -> Result<(), GitError> {
+        Err(GitError {
+            subcommand: "lfs".to_string(),
+            status: None,
+            stderr: "Git LFS is not supported by the libgit2 backend, use --git-backend cli"
+                .to_string(),
+            cwd: self.cwd.clone(),
+            auth_failure: false,
+        })
+    }
+
+    fn add(&self, path: String) -> Result<(), GitError> {
+        let repo = self.open("add")?;
+        let mut index = repo.index().map_err(|e| self.error("add", e))?;
+        index
+            .add_all([path], IndexAddOption::DEFAULT, None)
+            .and_then(|_| index.write())
+            .map_err(|e| self.error("add", e))
+    }
+
+    fn commit(&self, message: String) -> Result<(), GitError> {
+        let repo = self.open("commit")?;
+        let signature = repo
+            .signature()
+            .or_else(|_| Signature::now("grace", "grace@localhost"))
+            .map_err(|e| self.error("commit", e))?;
+
+        let mut index = repo.index().map_err(|e| self.error("commit", e))?;
+        let tree_id = index.write_tree().map_err(|e| self.error("commit", e))?;
+        let tree = repo
+            .find_tree(tree_id)
+            .map_err(|e| self.error("commit", e))?;
+
+        let parent = self.find_commit(&repo, "commit", "HEAD").ok();
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            &message,
+            &tree,
+            &parents,
+        )
+        .map_err(|e| self.error("commit", e))?;
+        Ok(())
+    }
+
+    fn push(&self, branch: String) -> Result<bool, GitError> {
+        let repo = self.open("push")?;
+        let mut remote = repo
+            .find_remote("origin")
+            .map_err(|e| self.error("push", e))?;
+
+        // libgit2 only reports rejected references through this callback
+        let mut rejected = None;
+        {
+            let mut callbacks = self.callbacks();
+            callbacks.push_update_reference(|reference, status| {
+                if let Some(status) = status {
+                    rejected = Some(format!("{} was rejected: {}", reference, status));
+                }
+                Ok(())
+            });
+            let mut options = PushOptions::new();
+            options.remote_callbacks(callbacks);
+            match remote.push(
+                &[&format!("HEAD:refs/heads/{}", branch)],
+                Some(&mut options),
+            ) {
+                Ok(_) => {}
+                Err(e) if e.code() == ErrorCode::NotFastForward => return Ok(false),
+                Err(e) => return Err(self.error("push", e)),
+            }
+        }
+
+        match rejected {
+            Some(reason) if NOT_FAST_FORWARD.iter().any(|x| reason.contains(x)) => Ok(false),
+            Some(reason) => Err(GitError {
+                subcommand: "push".to_string(),
+                status: None,
+                stderr: reason,
+                cwd: self.cwd.clone(),
+                auth_failure: false,
+            }),
+            None => Ok(true),
+        }
+    }
+
+    fn reset(&self, revision: String) -> Result<(), GitError> {
+        let repo = self.open("reset")?;
+        let commit = self.find_commit(&repo, "reset", &revision)?;
+        repo.reset(commit.as_object(), ResetType::Hard, None)
+            .map_err(|e| self.error("reset", e))
+    }
+
+    fn rev_parse(&self, revision: String) -> Result<String, GitError> {
+        let repo = self.open("rev-parse")?;
+        let commit = self.find_commit(&repo, "rev-parse", &revision)?;
+        Ok(commit.id().to_string())
+    }
+
+    fn remote_url(&self) -> Result<String, GitError> {
+        let repo = self.open("remote")?;
+        let remote = repo
+            .find_remote("origin")
+            .map_err(|e| self.error("remote", e))?;
+        Ok(remote.url().unwrap_or_default().to_string())
+    }
+
+    fn remote_contains(&self, commit_hash: String) -> Result<bool, GitError> {
+        let repo = self.open("fetch")?;
+        self.fetch_refspecs(&repo, "fetch", &["+refs/heads/*:refs/remotes/origin/*"])?;
+        let commit = self.find_commit(&repo, "for-each-ref", &commit_hash)?.id();
+
+        let references = repo
+            .references()
+            .map_err(|e| self.error("for-each-ref", e))?;
+        for reference in references.flatten() {
+            let name = reference.name().unwrap_or_default();
+            if !name.starts_with("refs/remotes/origin/") && !name.starts_with("refs/tags/") {
+                continue;
+            }
+            if let Ok(tip) = reference.peel_to_commit() {
+                if tip.id() == commit || repo.graph_descendant_of(tip.id(), commit).unwrap_or(false)
+                {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn has_commit(&self, commit_hash: String) -> bool {
+        self.open("cat-file")
+            .and_then(|repo| {
+                self.find_commit(&repo, "cat-file", &commit_hash)
+                    .map(|_| ())
+            })
+            .is_ok()
+    }
+
+    fn show(&self, commit_hash: String, file: String) -> Result<Option<String>, GitError> {
+        let repo = self.open("show")?;
+        let commit = self.find_commit(&repo, "show", &commit_hash)?;
+        let tree = commit.tree().map_err(|e| self.error("show", e))?;
+        let entry = match tree.get_path(Path::new(&file)) {
+            Ok(entry) => entry,
+            Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(self.error("show", e)),
+        };
+        let blob = entry
+            .to_object(&repo)
+            .and_then(|o| o.peel_to_blob())
+            .map_err(|e| self.error("show", e))?;
+        Ok(Some(
+            String::from_utf8_lossy(blob.content()).trim().to_string(),
+        ))
+    }
+}
+
+fn auth_error(message: &str) -> git2::Error {
+    git2::Error::new(ErrorCode::Auth, ErrorClass::Callback, message)
+}
+
+/// Asks the GIT_ASKPASS like program for a user name or password
+fn ask(program: &str, prompt: &str) -> Result<String, git2::Error> {
+    let output = Command::new(program)
+        .arg(prompt)
+        .output()
+        .map_err(|e| auth_error(&format!("failed to run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(auth_error(&format!(
+            "{} did not provide credentials",
+            program
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
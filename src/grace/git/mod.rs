@@ -1,29 +1,249 @@
-use std::{
-    io::Write,
-    process::{Command, Output},
-};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::OnceLock};
+
+mod cli;
+#[cfg(feature = "libgit2")]
+mod libgit2;
+
+/// The git operations grace needs. All operations work on the repository in
+/// the working directory the backend was created for.
+pub trait GitBackend {
+    /// Creates a repository, without a working tree if bare is set
+    fn init(&self, bare: bool) -> Result<(), GitError>;
+
+    /// Creates a new worktree at path that shares the objects of this
+    /// repository. The name identifies the worktree within the repository and
+    /// should be unique. Without checkout only HEAD is set to the commit and
+    /// the files are left to a later reset.
+    fn worktree_add(
+        &self,
+        name: String,
+        path: String,
+        commit_hash: String,
+        checkout: bool,
+    ) -> Result<(), GitError>;
+
+    /// Restricts the working tree to the given paths relative to its root, an
+    /// empty list checks out everything again
+    fn sparse_checkout(&self, paths: Vec<String>) -> Result<(), GitError>;
+
+    /// Returns the paths of a sparse checkout, empty if everything is checked
+    /// out. Sparse checkouts in cone mode always contain the files of the root
+    /// folder, these are reported as "/".
+    fn sparse_paths(&self) -> Result<Vec<String>, GitError>;
+
+    /// Adds the remote "origin"
+    fn remote(&self, remote: String) -> Result<(), GitError>;
+
+    fn fetch(&self) -> Result<(), GitError>;
+
+    /// Fetches exactly one commit from origin. With a depth only that many
+    /// commits of its history are fetched.
+    fn fetch_commit(&self, commit_hash: String, depth: Option<u32>) -> Result<(), GitError>;
+
+    /// Asks origin for the branch its HEAD points to
+    fn default_branch(&self) -> Result<String, GitError>;
+
+    fn pull(&self, branch: String) -> Result<(), GitError>;
+
+    /// Clones into a new folder or - if bare is set - into the working
+    /// directory itself. Without a branch the remote's default branch is
+    /// checked out.
+    fn clone(&self, uri: String, bare: bool, branch: Option<String>) -> Result<(), GitError>;
+
+    fn checkout(&self, commit_hash: String) -> Result<(), GitError>;
+
+    /// Initializes and checks out all submodules, recursively
+    fn submodule_update(&self) -> Result<(), GitError>;
+
+    /// Downloads the Git LFS objects of the checked out commit
+    fn lfs_pull(&self) -> Result<(), GitError>;
+
+    fn add(&self, path: String) -> Result<(), GitError>;
+
+    fn commit(&self, message: String) -> Result<(), GitError>;
+
+    /// Pushes HEAD to the given branch of origin. Returns false if origin
+    /// rejected the push because the branch moved on, i.e. it is not a fast
+    /// forward.
+    fn push(&self, branch: String) -> Result<bool, GitError>;
+
+    /// Throws away all local changes and moves HEAD to the given revision
+    fn reset(&self, revision: String) -> Result<(), GitError>;
+
+    /// Resolves a revision (e.g. HEAD) to the full commit hash
+    fn rev_parse(&self, revision: String) -> Result<String, GitError>;
+
+    /// Returns the URL of the remote "origin"
+    fn remote_url(&self) -> Result<String, GitError>;
+
+    /// Checks if the commit is present in the local repository
+    fn has_commit(&self, commit_hash: String) -> bool;
+
+    /// Fetches the branches and tags of origin and checks if one of them
+    /// contains the commit
+    fn remote_contains(&self, commit_hash: String) -> Result<bool, GitError>;
+
+    /// Returns the contents of a file at the given commit, None if the commit
+    /// has no such file
+    fn show(&self, commit_hash: String, file: String) -> Result<Option<String>, GitError>;
+}
+
+/// The available implementations of GitBackend
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Backend {
+    /// Runs the git executable
+    Cli,
+    /// Uses libgit2 in process, no git executable is needed
+    #[cfg(feature = "libgit2")]
+    Libgit2,
+}
+
+/// How the commits of packages are fetched
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    /// Only the commit itself without its history. Falls back to Full if the
+    /// remote does not allow this.
+    #[default]
+    Shallow,
+    /// The complete history of all branches
+    Full,
+}
+
+// Environment variable that selects the backend if none was given on the
+// command line
+pub const GIT_BACKEND_ENV: &str = "GRACE_GIT_BACKEND";
+
+static SELECTED_BACKEND: OnceLock<Backend> = OnceLock::new();
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        match data {
+            "cli" => Ok(Backend::Cli),
+            #[cfg(feature = "libgit2")]
+            "libgit2" => Ok(Backend::Libgit2),
+            #[cfg(not(feature = "libgit2"))]
+            "libgit2" => Err("grace was built without libgit2 support".to_string()),
+            _ => Err(format!("Unknown git backend {}", data)),
+        }
+    }
+}
+
+/// Selects the backend used by all GitClients created afterwards
+pub fn select_backend(backend: Backend) {
+    let _ = SELECTED_BACKEND.set(backend);
+}
+
+fn selected_backend() -> Backend {
+    // The command line validates the environment variable before anything
+    // else happens
+    *SELECTED_BACKEND.get_or_init(|| {
+        std::env::var(GIT_BACKEND_ENV)
+            .ok()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(Backend::Cli)
+    })
+}
+
+/// Credentials for a remote. Only the given options are passed to git, it
+/// falls back to the user's own configuration for everything else.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Credentials {
+    /// Private key used for ssh remotes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<String>,
+    /// Program that is asked for user name and password, see GIT_ASKPASS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub askpass: Option<String>,
+    /// Name of the environment variable holding an access token for https
+    /// remotes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+    /// User name sent along with the token, defaults to "oauth2"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+impl Credentials {
+    /// Returns the path of the ssh key with a leading ~ expanded
+    pub fn ssh_key_path(&self) -> Option<String> {
+        let key = self.ssh_key.as_ref()?;
+        match (key.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => Some(home.join(rest).to_str().unwrap().to_string()),
+            _ => Some(key.clone()),
+        }
+    }
+
+    pub fn username(&self) -> String {
+        self.username.clone().unwrap_or("oauth2".to_string())
+    }
+
+    /// Reads the token from its environment variable
+    pub fn token(&self) -> Option<Result<String, String>> {
+        let name = self.token_env.as_ref()?;
+        Some(match std::env::var(name) {
+            Ok(token) if !token.is_empty() => Ok(token),
+            _ => Err(format!("the environment variable {} is not set", name)),
+        })
+    }
+}
 
 pub struct GitClient {
     cwd: String,
     silent: bool,
-    err: bool,
+    backend: Backend,
+    credentials: Option<Credentials>,
+}
+
+// Messages of git and libgit2 for pushes that were rejected because the
+// remote branch moved on
+const NOT_FAST_FORWARD: [&str; 3] = ["non-fast-forward", "fetch first", "non-fastforwardable"];
+
+/// A failed git operation
+#[derive(Debug)]
+pub struct GitError {
+    /// The git subcommand that failed, e.g. "fetch"
+    pub subcommand: String,
+    /// Exit status of git, None if git could not be started at all or the
+    /// operation was run in process
+    pub status: Option<i32>,
+    pub stderr: String,
+    pub cwd: String,
+    /// Set if the remote rejected the credentials or none were available
+    pub auth_failure: bool,
 }
 
+impl std::fmt::Display for GitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.auth_failure {
+            write!(f, "authentication failed, ")?;
+        }
+        write!(f, "git {} failed in {}", self.subcommand, self.cwd)?;
+        if let Some(status) = self.status {
+            write!(f, " (exit status {})", status)?;
+        }
+
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            write!(f, ": {}", stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GitError {}
+
 impl GitClient {
     pub fn create() -> Self {
         Self {
             cwd: ".".to_string(),
             silent: false,
-            err: false,
-        }
-    }
-
-    fn process_output(&self, o: Output) -> bool {
-        if !self.silent {
-            std::io::stdout().write_all(&o.stdout).unwrap();
-            std::io::stderr().write_all(&o.stderr).unwrap();
+            backend: selected_backend(),
+            credentials: None,
         }
-        return o.status.success();
     }
 
     pub fn cwd(mut self, cwd: String) -> Self {
@@ -36,89 +256,142 @@ impl GitClient {
         self
     }
 
-    pub fn fetch(mut self) -> Self {
-        let mut git = Command::new("git");
-
-        self.err = self.process_output(
-            git.current_dir(self.cwd.clone())
-                .args(["fetch".to_string()])
-                .output()
-                .expect("FETCH failed"),
-        );
+    /// Uses the credentials for all operations that talk to the remote
+    pub fn credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.credentials = credentials;
         self
     }
 
-    pub fn init(mut self) -> Self {
-        let mut git = Command::new("git");
+    fn backend(&self) -> Box<dyn GitBackend> {
+        match self.backend {
+            Backend::Cli => Box::new(cli::CliBackend::new(
+                self.cwd.clone(),
+                self.silent,
+                self.credentials.clone(),
+            )),
+            #[cfg(feature = "libgit2")]
+            Backend::Libgit2 => Box::new(libgit2::Git2Backend::new(
+                self.cwd.clone(),
+                self.credentials.clone(),
+            )),
+        }
+    }
+
+    pub fn fetch(&self) -> Result<(), GitError> {
+        self.backend().fetch()
+    }
 
-        self.err = self.process_output(
-            git.current_dir(self.cwd.clone())
-                .args(["init".to_string()])
-                .output()
-                .expect("INIT failed"),
-        );
-        self
+    pub fn init(&self, bare: bool) -> Result<(), GitError> {
+        self.backend().init(bare)
     }
 
-    pub fn remote(mut self, remote: String) -> Self {
-        let mut git = Command::new("git");
-
-        self.err = self.process_output(
-            git.current_dir(self.cwd.clone())
-                .args([
-                    "remote".to_string(),
-                    "add".to_string(),
-                    "origin".to_string(),
-                    remote,
-                ])
-                .output()
-                .expect("INIT failed"),
-        );
-        self
+    pub fn worktree_add(
+        &self,
+        name: String,
+        path: String,
+        commit_hash: String,
+        checkout: bool,
+    ) -> Result<(), GitError> {
+        self.backend()
+            .worktree_add(name, path, commit_hash, checkout)
     }
 
-    pub fn clone(mut self, uri: String, bare: bool) -> Self {
-        let mut git = Command::new("git");
-        let args = if !bare {
-            vec!["clone".to_string(), uri]
-        } else {
-            vec!["clone".to_string(), uri, ".".to_string()]
-        };
-
-        self.err = self.process_output(
-            git.current_dir(self.cwd.clone())
-                .args(args)
-                .output()
-                .expect("CLONE failed"),
-        );
-        self
+    pub fn sparse_checkout(&self, paths: Vec<String>) -> Result<(), GitError> {
+        self.backend().sparse_checkout(paths)
     }
 
-    pub fn pull(mut self) -> Self {
-        let mut git = Command::new("git");
+    pub fn sparse_paths(&self) -> Result<Vec<String>, GitError> {
+        self.backend().sparse_paths()
+    }
 
-        self.err = self.process_output(
-            git.current_dir(self.cwd.clone())
-                .args(["pull", "origin", "master"])
-                .output()
-                .expect("PULL failed"),
-        );
-        self
+    pub fn remote(&self, remote: String) -> Result<(), GitError> {
+        self.backend().remote(remote)
     }
 
-    pub fn checkout(mut self, commit_hash: String) -> Self {
-        let mut git = Command::new("git");
+    /// Makes the commit available locally
+    pub fn fetch_commit(&self, commit_hash: String, mode: FetchMode) -> Result<(), GitError> {
+        let backend = self.backend();
+        if mode == FetchMode::Shallow {
+            // Servers may refuse to hand out commits no branch points to
+            match backend.fetch_commit(commit_hash.clone(), Some(1)) {
+                Ok(_) => return Ok(()),
+                Err(e) => println!(
+                    "  Shallow fetch failed, fetching everything instead. ({})",
+                    e
+                ),
+            }
+        }
 
-        self.err = self.process_output(
-            git.current_dir(self.cwd.clone())
-                .args(["checkout".to_string(), commit_hash])
-                .output()
-                .expect("CHECKOUT failed"),
-        );
-        self
+        backend.fetch()?;
+        if !backend.has_commit(commit_hash.clone()) {
+            return Err(GitError {
+                subcommand: "fetch".to_string(),
+                status: None,
+                stderr: format!("origin does not contain commit {}", commit_hash),
+                cwd: self.cwd.clone(),
+                auth_failure: false,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn default_branch(&self) -> Result<String, GitError> {
+        self.backend().default_branch()
+    }
+
+    pub fn clone(&self, uri: String, bare: bool, branch: Option<String>) -> Result<(), GitError> {
+        self.backend().clone(uri, bare, branch)
+    }
+
+    pub fn pull(&self, branch: String) -> Result<(), GitError> {
+        self.backend().pull(branch)
+    }
+
+    pub fn checkout(&self, commit_hash: String) -> Result<(), GitError> {
+        self.backend().checkout(commit_hash)
+    }
+
+    pub fn submodule_update(&self) -> Result<(), GitError> {
+        self.backend().submodule_update()
+    }
+
+    pub fn lfs_pull(&self) -> Result<(), GitError> {
+        self.backend().lfs_pull()
+    }
+
+    pub fn add(&self, path: String) -> Result<(), GitError> {
+        self.backend().add(path)
+    }
+
+    pub fn commit(&self, message: String) -> Result<(), GitError> {
+        self.backend().commit(message)
+    }
+
+    pub fn push(&self, branch: String) -> Result<bool, GitError> {
+        self.backend().push(branch)
+    }
+
+    pub fn reset(&self, revision: String) -> Result<(), GitError> {
+        self.backend().reset(revision)
+    }
+
+    pub fn rev_parse(&self, revision: String) -> Result<String, GitError> {
+        self.backend().rev_parse(revision)
+    }
+
+    pub fn remote_url(&self) -> Result<String, GitError> {
+        self.backend().remote_url()
+    }
+
+    pub fn has_commit(&self, commit_hash: String) -> bool {
+        self.backend().has_commit(commit_hash)
+    }
+
+    pub fn remote_contains(&self, commit_hash: String) -> Result<bool, GitError> {
+        self.backend().remote_contains(commit_hash)
     }
 
-    pub(crate) fn err(&self) -> bool {
-        self.err
+    pub fn show(&self, commit_hash: String, file: String) -> Result<Option<String>, GitError> {
+        self.backend().show(commit_hash, file)
     }
 }
@@ -1,11 +1,23 @@
 use serde::{Deserialize, Serialize};
 
-mod git;
+pub mod cache;
+pub mod git;
 pub mod package;
 pub mod project;
+pub mod registry;
+pub mod resolver;
 pub mod semver;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Registry {
     pub uri: String,
+
+    /// The branch holding the index, the remote's default branch is used if
+    /// none is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+
+    /// Credentials for the registry and the packages it lists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<git::Credentials>,
 }
@@ -1,5 +1,5 @@
 use std::{
-    fs::{File},
+    fs::File,
     io::{self, BufRead, BufReader, Write},
     path::PathBuf,
 };
@@ -7,25 +7,74 @@ use std::{
 use serde::{Deserialize, Serialize};
 
 use super::{
-    git,
-    project::{Project, GRACE_PACKAGE_FILE_NAME, GRACE_PACKAGE_LOCK_FILE_NAME},
-    semver::SemanticVersion,
+    cache,
+    git::{self, GitError},
+    project::{
+        Project, GRACE_PACKAGE_FILE_NAME, GRACE_PACKAGE_LOCK_FILE_NAME, REGISTRY_SCHEMA_VERSION,
+    },
+    resolver::{PackageSource, ResolveError, Resolver},
+    semver::{Compatibility, SemanticVersion, VersionParseError, VersionReq},
 };
 
+/// Controls how much install is allowed to change
 #[derive(PartialEq, Copy, Clone)]
-pub enum VersionSelector {
-    /// Versions must match each other
-    StrictEquals, // =
-    /// found version must be larger or same than requested one
-    LargerEquals, // >=
-    /// found version must have same minor and major as requested one
-    Compatible, // ~=
+pub enum InstallMode {
+    /// Resolve packages that are not locked and update grace-lock.json
+    Normal,
+    /// Fail if grace-lock.json does not match grace-packages.txt
+    Locked,
+    /// Like Locked, but also fail if a package would have to be fetched
+    Frozen,
 }
 
+/// Reasons for an install or update to fail
+#[derive(Debug)]
+pub enum InstallError {
+    /// grace-lock.json would have to be modified
+    LockfileOutdated(Vec<String>),
+    /// Packages would have to be fetched from the network
+    NetworkRequired(Vec<String>),
+    /// No combination of package versions satisfies all requests
+    Unresolvable(String),
+    /// A package (transitively) depends on itself
+    DependencyCycle(Vec<String>),
+    /// Checking out packages failed, contains one message per package
+    InstallFailed(Vec<String>),
+    /// The packages to update are neither requested nor locked
+    UnknownPackage(Vec<String>),
+}
+
+pub const EXIT_UNKNOWN_PACKAGE: i32 = 2;
+pub const EXIT_LOCKFILE_OUTDATED: i32 = 3;
+pub const EXIT_NETWORK_REQUIRED: i32 = 4;
+pub const EXIT_DEPENDENCY_CYCLE: i32 = 5;
+pub const EXIT_UNRESOLVABLE: i32 = 6;
+pub const EXIT_INSTALL_FAILED: i32 = 7;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PackageVersion {
     pub id: String,
     pub commit_hash: String,
+    #[serde(flatten)]
+    pub checkout: CheckoutOptions,
+}
+
+/// Controls how a package is checked out
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CheckoutOptions {
+    /// Initialize and update all submodules, recursively
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub submodules: bool,
+    /// Download the Git LFS objects
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub lfs: bool,
+    /// Only check out these folders of the package, all of it if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sparse: Vec<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -37,20 +86,274 @@ pub struct Package {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PackageList {
+    /// Version of the index format, indexes without one use the first version
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
     pub packagelist: Vec<Package>,
 }
 
+fn default_schema_version() -> u32 {
+    1
+}
+
+impl PackageList {
+    /// Reads an index.json. Packages and versions that do not have the
+    /// expected structure are skipped, the second value describes each of
+    /// them. Only an index that is not a package list at all is an error.
+    pub fn parse(data: &str) -> Result<(Self, Vec<String>), String> {
+        #[derive(Deserialize)]
+        struct RawList {
+            #[serde(default = "default_schema_version")]
+            schema_version: u32,
+            packagelist: Vec<serde_json::Value>,
+        }
+
+        #[derive(Deserialize)]
+        struct RawPackage {
+            name: String,
+            uri: String,
+            #[serde(default)]
+            versions: Vec<serde_json::Value>,
+        }
+
+        let raw: RawList = serde_json::from_str(data).map_err(|e| e.to_string())?;
+        let mut skipped = vec![];
+        let mut list = Self {
+            schema_version: raw.schema_version,
+            packagelist: vec![],
+        };
+
+        for (index, entry) in raw.packagelist.into_iter().enumerate() {
+            let raw_package: RawPackage = match serde_json::from_value(entry) {
+                Ok(p) => p,
+                Err(e) => {
+                    skipped.push(format!("package #{}: {}", index + 1, e));
+                    continue;
+                }
+            };
+
+            let mut package = Package {
+                name: raw_package.name,
+                uri: raw_package.uri,
+                versions: vec![],
+            };
+            for version in raw_package.versions {
+                match serde_json::from_value(version) {
+                    Ok(v) => package.versions.push(v),
+                    Err(e) => skipped.push(format!("a version of {}: {}", package.name, e)),
+                }
+            }
+            list.packagelist.push(package);
+        }
+        Ok((list, skipped))
+    }
+}
+
+impl Default for PackageList {
+    fn default() -> Self {
+        Self {
+            schema_version: REGISTRY_SCHEMA_VERSION,
+            packagelist: vec![],
+        }
+    }
+}
+
+/// A single line of grace-packages.txt
+#[derive(Clone)]
+pub struct PackageRequest {
+    pub name: String,
+    pub requirement: VersionReq,
+    pub checkout: CheckoutOptions,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PackageDependency {
     pub name: String,
     pub version: String,
     pub uri: String,
     pub commit_hash: String,
+    #[serde(flatten)]
+    pub checkout: CheckoutOptions,
 }
 
 impl PackageVersion {
-    pub fn as_semver(&self) -> SemanticVersion {
-        return SemanticVersion::from_string(self.id.clone());
+    pub fn as_semver(&self) -> Result<SemanticVersion, VersionParseError> {
+        self.id.parse()
+    }
+}
+
+impl CheckoutOptions {
+    /// Parses a list of form "submodules, lfs, sparse=src/lib"
+    fn parse(data: &str) -> Result<Self, String> {
+        let mut options = Self::default();
+        for option in data.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match option.split_once('=') {
+                None if option == "submodules" => options.submodules = true,
+                None if option == "lfs" => options.lfs = true,
+                Some(("sparse", path)) => {
+                    let path = path.trim().trim_matches('/');
+                    if path.is_empty() || path.split('/').any(|x| x == "..") {
+                        return Err(format!("Invalid sparse path {}", option));
+                    }
+                    options.sparse.push(path.to_string());
+                }
+                _ => return Err(format!("Unknown option {}", option)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Combines the steps and sparse paths of both options
+    pub fn merge(self, other: Self) -> Self {
+        let mut sparse = self.sparse;
+        for path in other.sparse {
+            if !sparse.contains(&path) {
+                sparse.push(path);
+            }
+        }
+
+        Self {
+            submodules: self.submodules || other.submodules,
+            lfs: self.lfs || other.lfs,
+            sparse,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl std::fmt::Display for CheckoutOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut options: Vec<String> = vec![];
+        if self.submodules {
+            options.push("submodules".to_string());
+        }
+        if self.lfs {
+            options.push("lfs".to_string());
+        }
+        for path in self.sparse.iter() {
+            options.push(format!("sparse={}", path));
+        }
+        write!(f, "[{}]", options.join(", "))
+    }
+}
+
+impl std::fmt::Display for PackageRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.requirement)?;
+        if !self.checkout.is_empty() {
+            write!(f, " {}", self.checkout)?;
+        }
+        Ok(())
+    }
+}
+
+impl InstallError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InstallError::LockfileOutdated(_) => EXIT_LOCKFILE_OUTDATED,
+            InstallError::NetworkRequired(_) => EXIT_NETWORK_REQUIRED,
+            InstallError::DependencyCycle(_) => EXIT_DEPENDENCY_CYCLE,
+            InstallError::Unresolvable(_) => EXIT_UNRESOLVABLE,
+            InstallError::InstallFailed(_) => EXIT_INSTALL_FAILED,
+            InstallError::UnknownPackage(_) => EXIT_UNKNOWN_PACKAGE,
+        }
+    }
+}
+
+impl std::fmt::Display for InstallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstallError::LockfileOutdated(reasons) => write!(
+                f,
+                "{} does not match {}:\n  {}",
+                GRACE_PACKAGE_LOCK_FILE_NAME,
+                GRACE_PACKAGE_FILE_NAME,
+                reasons.join("\n  ")
+            ),
+            InstallError::NetworkRequired(packages) => write!(
+                f,
+                "The following packages are not available offline: {}",
+                packages.join(", ")
+            ),
+            InstallError::DependencyCycle(packages) => {
+                write!(f, "Dependency cycle detected: {}", packages.join(" -> "))
+            }
+            InstallError::Unresolvable(explanation) => {
+                write!(f, "Failed to resolve dependencies:\n{}", explanation)
+            }
+            InstallError::InstallFailed(failures) => write!(
+                f,
+                "Failed to install the following packages:\n  {}",
+                failures.join("\n  ")
+            ),
+            InstallError::UnknownPackage(packages) => write!(
+                f,
+                "The following packages are neither listed in {} nor in {}: {}",
+                GRACE_PACKAGE_FILE_NAME,
+                GRACE_PACKAGE_LOCK_FILE_NAME,
+                packages.join(", ")
+            ),
+        }
+    }
+}
+
+impl PackageRequest {
+    /// Parses a line of form <package> <requirement> [options], e.g.
+    /// "APackage >=1.2, <1.5 [submodules, lfs]". The options are optional.
+    pub fn parse(data: &str) -> Result<Self, String> {
+        let (line, checkout) = match data.split_once('[') {
+            Some((line, options)) => {
+                let options = options.trim().strip_suffix(']').ok_or_else(|| {
+                    format!("Expected the options to end with ']'. Got: {}", data)
+                })?;
+                let checkout = CheckoutOptions::parse(options)
+                    .map_err(|e| format!("Invalid options in \"{}\": {}", data, e))?;
+                (line, checkout)
+            }
+            None => (data, CheckoutOptions::default()),
+        };
+
+        let (name, requirement) = line.trim().split_once(char::is_whitespace).ok_or_else(|| {
+            format!(
+                "Expected a package of form <package> <requirement>. Got: {}",
+                data
+            )
+        })?;
+
+        Ok(Self {
+            name: name.to_string(),
+            requirement: requirement
+                .parse()
+                .map_err(|e| format!("Invalid requirement in \"{}\": {}", data, e))?,
+            checkout,
+        })
+    }
+
+    /// Like parse, but panics on invalid lines
+    pub fn from_string(data: String) -> Self {
+        Self::parse(&data).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Reads all package requests from grace-packages.txt
+    pub fn read_all(path: PathBuf) -> Vec<Self> {
+        let mut cfg_file = path.clone();
+        cfg_file.push(GRACE_PACKAGE_FILE_NAME);
+        let file = File::open(cfg_file).unwrap_or_else(|_| {
+            panic!(
+                "No package file ({}) is available.",
+                GRACE_PACKAGE_FILE_NAME
+            )
+        });
+
+        io::BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter(|x| !x.trim().is_empty())
+            .map(Self::from_string)
+            .collect()
     }
 }
 
@@ -58,12 +361,10 @@ impl PackageDependency {
     pub fn get_package_list(path: PathBuf) -> Vec<Self> {
         let mut grace_file = path.clone();
         grace_file.push(GRACE_PACKAGE_LOCK_FILE_NAME);
-        let file: File;
         if !grace_file.exists() {
             return vec![];
-        } else {
-            file = File::open(grace_file).expect(".grace file is missing");
         }
+        let file = File::open(grace_file).expect(".grace file is missing");
 
         let reader = BufReader::new(file);
         let data: Vec<Self> =
@@ -79,112 +380,800 @@ impl PackageDependency {
         let _ = file.write_all(serde_json::to_string(&data).unwrap().as_bytes());
     }
 
-    /// Process grace-package.txt and install all packages found there
-    pub(crate) fn install(path: PathBuf) {
-        let mut cfg_file = path.clone();
-        cfg_file.push(GRACE_PACKAGE_FILE_NAME);
-        let file = File::open(cfg_file).expect(
-            format!(
-                "No package file ({}) is available.",
-                GRACE_PACKAGE_FILE_NAME
-            )
-            .as_str(),
-        );
+    /// Checks if this (locked) dependency still fulfills a request from grace-packages.txt
+    pub fn satisfies(&self, request: &PackageRequest) -> bool {
+        self.name == request.name
+            && self
+                .as_semver()
+                .is_ok_and(|v| request.requirement.matches(&v))
+    }
+
+    pub fn as_semver(&self) -> Result<SemanticVersion, VersionParseError> {
+        self.version.parse()
+    }
+
+    /// Process grace-package.txt and install all packages found there. Packages
+    /// that are already locked in a matching version are not resolved again.
+    /// If a package has a grace-packages.txt of its own, its dependencies are
+    /// installed as well and locked in the root project.
+    pub(crate) fn install(path: PathBuf, mode: InstallMode) -> Result<(), InstallError> {
+        let project = Project::open(path.clone());
+        let locked = Self::get_package_list(path.clone());
+        let requests = PackageRequest::read_all(path.clone());
 
-        for line in io::BufReader::new(file).lines() {
-            if let Ok(ln) = line {
-                let items: Vec<&str> = ln.split(&[' ']).collect();
-                if items.len() != 3 {
-                    panic!("Expected a version of form <packag> <selector> <version> (note the whitespace!). Got: {}", ln);
+        if mode != InstallMode::Normal {
+            let outdated: Vec<String> = requests
+                .iter()
+                .filter(|r| !locked.iter().any(|x| x.satisfies(r)))
+                .map(|r| r.to_string())
+                .collect();
+            if !outdated.is_empty() {
+                return Err(InstallError::LockfileOutdated(outdated));
+            }
+        }
+
+        let mut source = RegistrySource {
+            project: &project,
+            locked: &locked,
+            mode,
+        };
+        let mut resolved = Self::resolve(&mut source, locked.clone(), requests.clone(), mode)?;
+
+        if mode != InstallMode::Normal {
+            let stale: Vec<String> = locked
+                .iter()
+                .filter(|x| !resolved.iter().any(|r| r.name == x.name))
+                .map(|x| format!("{} is no longer required", x.name))
+                .collect();
+            if !stale.is_empty() {
+                return Err(InstallError::LockfileOutdated(stale));
+            }
+        }
+
+        // In --locked and --frozen mode the locked options are used, they
+        // must match the ones the requests ask for
+        let mut requested = resolved.clone();
+        Self::apply_requested_options(&mut source, requests, &mut requested);
+        if mode == InstallMode::Normal {
+            resolved = requested;
+        } else {
+            let outdated = Self::changed_options(&resolved, &requested);
+            if !outdated.is_empty() {
+                return Err(InstallError::LockfileOutdated(outdated));
+            }
+        }
+
+        // Submodules and LFS objects are fetched from their remotes, that
+        // only works in --frozen mode if there is nothing to do
+        if mode == InstallMode::Frozen {
+            let offline: Vec<String> = resolved
+                .iter()
+                .filter(|x| x.checkout.submodules || x.checkout.lfs)
+                .filter(|x| !Self::is_checked_out(&project, x))
+                .map(|x| x.name.clone())
+                .collect();
+            if !offline.is_empty() {
+                return Err(InstallError::NetworkRequired(offline));
+            }
+        }
+
+        let (installed, failures) = Self::install_all(&project, &locked, resolved);
+        if mode == InstallMode::Normal {
+            Self::store_package_list(path, installed);
+        }
+        if !failures.is_empty() {
+            return Err(InstallError::InstallFailed(failures));
+        }
+        Ok(())
+    }
+
+    /// Moves all packages to the newest versions that still satisfy all
+    /// requests, including the ones of other packages. If package_names is not
+    /// empty only these packages are updated, all others keep their locked
+    /// version if possible.
+    pub(crate) fn update(path: PathBuf, package_names: Vec<String>) -> Result<(), InstallError> {
+        let project = Project::open(path.clone());
+        let locked = Self::get_package_list(path.clone());
+        let requests = PackageRequest::read_all(path.clone());
+
+        let unknown: Vec<String> = package_names
+            .iter()
+            .filter(|name| {
+                !requests.iter().any(|x| &x.name == *name)
+                    && !locked.iter().any(|x| &x.name == *name)
+            })
+            .cloned()
+            .collect();
+        if !unknown.is_empty() {
+            return Err(InstallError::UnknownPackage(unknown));
+        }
+
+        // Packages that are not updated stick to their locked version. The
+        // whole lock is offered to the resolver, so locked versions that were
+        // removed from the index remain candidates.
+        let preferred: Vec<PackageDependency> = locked
+            .iter()
+            .filter(|x| !package_names.is_empty() && !package_names.contains(&x.name))
+            .cloned()
+            .collect();
+
+        let mut source = RegistrySource {
+            project: &project,
+            locked: &locked,
+            mode: InstallMode::Normal,
+        };
+        let mut resolved = Self::resolve(
+            &mut source,
+            preferred,
+            requests.clone(),
+            InstallMode::Normal,
+        )?;
+        Self::apply_requested_options(&mut source, requests, &mut resolved);
+        let resolved = Self::keep_unless_newer(&locked, resolved);
+
+        let updated: Vec<(String, Option<String>, String)> = resolved
+            .iter()
+            .filter_map(|dep| {
+                let current = locked.iter().find(|x| x.name == dep.name);
+                match current {
+                    Some(c) if c.commit_hash == dep.commit_hash => None,
+                    _ => Some((
+                        dep.name.clone(),
+                        current.map(|x| x.version.clone()),
+                        dep.version.clone(),
+                    )),
                 }
+            })
+            .collect();
 
-                let version = SemanticVersion::from_string(items[2].to_string());
-
-                let version_selector = match items[1] {
-                    ">=" => VersionSelector::LargerEquals,
-                    "=" => VersionSelector::StrictEquals,
-                    "~=" => VersionSelector::Compatible,
-                    _ => panic!("Invalid version selector!"),
-                };
-
-                println!("Installing package {}", items[0].to_string());
-                let dep = Self::add_package(
-                    path.clone(),
-                    items[0].to_string(),
-                    version_selector,
-                    version,
-                );
+        let (installed, failures) = Self::install_all(&project, &locked, resolved);
+        Self::store_package_list(path, installed);
+
+        if updated.is_empty() && failures.is_empty() {
+            println!("All packages are up to date.");
+        } else if !updated.is_empty() {
+            println!("Updated packages:");
+        }
+        for (name, old, new) in updated {
+            match old {
+                Some(old) => {
+                    // Requirements such as >=1.0 allow major updates, point these out
+                    let breaking = match (old.parse::<SemanticVersion>(), new.parse()) {
+                        (Ok(a), Ok(b)) => a.match_to(&b) == Compatibility::Breaking,
+                        _ => false,
+                    };
+                    let note = if breaking { " (breaking)" } else { "" };
+                    println!("  {} {} -> {}{}", name, old, new, note)
+                }
+                None => println!("  {} (new) -> {}", name, new),
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(InstallError::InstallFailed(failures));
+        }
+        Ok(())
+    }
+
+    /// Runs the resolver and translates its errors for the given mode
+    fn resolve(
+        source: &mut RegistrySource,
+        preferred: Vec<PackageDependency>,
+        requests: Vec<PackageRequest>,
+        mode: InstallMode,
+    ) -> Result<Vec<PackageDependency>, InstallError> {
+        match Resolver::new(source, preferred).resolve(requests) {
+            Ok(r) => Ok(r),
+            Err(ResolveError::Conflict(explanation)) => Err(if mode == InstallMode::Normal {
+                InstallError::Unresolvable(explanation)
+            } else {
+                InstallError::LockfileOutdated(vec![explanation])
+            }),
+            Err(ResolveError::Cycle(cycle)) => Err(InstallError::DependencyCycle(cycle)),
+            Err(ResolveError::InvalidPackage(explanation)) => {
+                Err(InstallError::Unresolvable(explanation))
+            }
+            Err(ResolveError::Unavailable(package)) => Err(if mode == InstallMode::Frozen {
+                InstallError::NetworkRequired(vec![package])
+            } else {
+                InstallError::Unresolvable(format!("Failed to fetch {}", package))
+            }),
+        }
+    }
 
-                if !Self::install_single_dependency(path.clone(), dep.clone()) {
-                    println!("Failed to install dependency {}", dep.name);
-                } else {
-                    println!("Installed successfully @ {}", dep.version);
+    /// Requests may ask for submodules, LFS or sparse paths on top of what the
+    /// registry says, no matter if they come from the project or another package
+    fn apply_requested_options(
+        source: &mut RegistrySource,
+        requests: Vec<PackageRequest>,
+        resolved: &mut [PackageDependency],
+    ) {
+        let mut all_requests = requests;
+        for dep in resolved.iter() {
+            all_requests.extend(source.dependencies(dep).unwrap_or_default());
+        }
+        for dep in resolved.iter_mut() {
+            let requests: Vec<&PackageRequest> =
+                all_requests.iter().filter(|x| x.name == dep.name).collect();
+            for request in requests.iter() {
+                dep.checkout = dep.checkout.clone().merge(request.checkout.clone());
+            }
+            // a request without sparse paths needs the whole package
+            if requests.iter().any(|x| x.checkout.sparse.is_empty()) {
+                dep.checkout.sparse.clear();
+            }
+        }
+    }
+
+    /// Updates never move a package to an older version, packages for which
+    /// no newer version was found keep their locked entry
+    fn keep_unless_newer(
+        locked: &[PackageDependency],
+        resolved: Vec<PackageDependency>,
+    ) -> Vec<PackageDependency> {
+        resolved
+            .into_iter()
+            .map(|dep| match locked.iter().find(|x| x.name == dep.name) {
+                Some(current) if current.as_semver().ok() >= dep.as_semver().ok() => {
+                    PackageDependency {
+                        checkout: dep.checkout,
+                        ..current.clone()
+                    }
                 }
+                _ => dep,
+            })
+            .collect()
+    }
+
+    /// Lists the packages whose locked CheckoutOptions differ from the requested
+    fn changed_options(
+        locked: &[PackageDependency],
+        requested: &[PackageDependency],
+    ) -> Vec<String> {
+        locked
+            .iter()
+            .zip(requested.iter())
+            .filter(|(locked, requested)| locked.checkout != requested.checkout)
+            .map(|(locked, requested)| {
+                format!(
+                    "{} is locked with {} instead of {}",
+                    locked.name, locked.checkout, requested.checkout
+                )
+            })
+            .collect()
+    }
+
+    /// Checks out all resolved packages. Returns the packages to lock along with
+    /// the failures, a package that failed keeps its previously locked version.
+    fn install_all(
+        project: &Project,
+        locked: &[PackageDependency],
+        resolved: Vec<PackageDependency>,
+    ) -> (Vec<PackageDependency>, Vec<String>) {
+        let mut failures = vec![];
+        let mut installed = vec![];
+        for dep in resolved {
+            println!("Installing package {}", dep.name);
+            if locked.iter().any(|x| x.commit_hash == dep.commit_hash) {
+                println!("  Using locked version {}", dep.version);
             }
+
+            // In --frozen mode the resolver made sure all commits are
+            // available already, so this never talks to the remote.
+            let previous = locked.iter().find(|x| x.name == dep.name);
+            match Self::install_single_dependency(project, dep.clone(), previous) {
+                Ok(true) => println!("Installed successfully @ {}", dep.version),
+                Ok(false) => println!("Already installed @ {}", dep.version),
+                Err(e) => {
+                    println!("Failed to install dependency {}: {}", dep.name, e);
+                    failures.push(format!("{}: {}", dep.name, e));
+                    installed.extend(locked.iter().find(|x| x.name == dep.name).cloned());
+                    continue;
+                }
+            }
+            installed.push(dep);
         }
+        (installed, failures)
+    }
+
+    /// Checks if the locked commit is available without talking to the remote
+    fn is_available_offline(project: &Project, dep: &PackageDependency) -> bool {
+        Self::find_repository(project, dep)
+            .is_some_and(|git| git.has_commit(dep.commit_hash.clone()))
+    }
+
+    /// Checks if the package's checkout is at the locked commit already
+    fn is_checked_out(project: &Project, dep: &PackageDependency) -> bool {
+        Self::has_checkout(project, &dep.name)
+            && Self::open_checkout(project, dep)
+                .and_then(|git| git.rev_parse("HEAD".to_string()))
+                .is_ok_and(|head| head == dep.commit_hash)
     }
 
-    fn install_single_dependency(path: PathBuf, dep: PackageDependency) -> bool {
-        let repo = dep.uri;
-        let commit = dep.commit_hash;
-        let mut target_dir = path.clone();
-        target_dir.push("packages");
-        let mut package_dir = target_dir.clone();
-        package_dir.push(dep.name.clone());
+    /// Checks if the package has a usable checkout, stale worktrees don't
+    /// count
+    fn has_checkout(project: &Project, name: &str) -> bool {
+        let package_dir = project.package_dir(name);
+        package_dir.join(".git").exists() && !cache::is_stale_worktree(&package_dir)
+    }
 
-        if !package_dir.exists() {
-            let _ = std::fs::create_dir(package_dir.clone());
+    /// Returns the repository holding the package's commits: its checkout if
+    /// there is one, the shared cache otherwise
+    fn find_repository(project: &Project, dep: &PackageDependency) -> Option<git::GitClient> {
+        if !Self::has_checkout(project, &dep.name) {
+            return cache::find(&dep.uri, project.package_credentials(&dep.uri));
         }
 
-        // Trouble: git insists on creating a new folder for the clone
-        // but we don't know ahead of time, what that folder is called
-        // howver, we have to enter the folder in order to be able to
-        // checkout the commit attached to the package
-        let git = git::GitClient::create();
-        git.cwd(package_dir.to_str().unwrap().to_string())
-            .silent()
-            .init()
-            .remote(repo)
-            .pull()
-            .fetch()
-            .checkout(commit)
-            .err()
-    }
-
-    pub fn add_package(
-        path: PathBuf,
-        package_name: String,
-        version_selector: VersionSelector,
-        package_version: SemanticVersion,
-    ) -> PackageDependency {
-        let p = Project::open(path.clone());
-        if let Some(package) = p.resolve_package(
-            package_name.clone(),
-            package_version.clone(),
-            version_selector,
-        ) {
-            let this = PackageDependency::get_package_list(path.clone());
-
-            let mut actual_list: Vec<PackageDependency> = this
-                .into_iter()
-                .filter(|x| x.name != package_name)
-                .collect();
+        let package_dir = project.package_dir(&dep.name);
+        Some(
+            git::GitClient::create()
+                .cwd(package_dir.to_str().unwrap().to_string())
+                .silent()
+                .credentials(project.package_credentials(&dep.uri)),
+        )
+    }
 
-            let dep = PackageDependency {
-                name: package_name,
-                version: package.0.id,
-                uri: package.1,
-                commit_hash: package.0.commit_hash,
-            };
+    /// Brings the package's checkout to the locked commit and runs the steps of
+    /// its CheckoutOptions. Returns false if the checkout was up to date already.
+    fn install_single_dependency(
+        project: &Project,
+        dep: PackageDependency,
+        previous: Option<&PackageDependency>,
+    ) -> Result<bool, GitError> {
+        let changed = Self::checkout_commit(project, &dep)?;
+
+        // The steps only run if the checkout changed or they were enabled
+        // since the previous install
+        let options_changed = previous.is_none_or(|x| {
+            x.commit_hash != dep.commit_hash
+                || x.checkout.submodules != dep.checkout.submodules
+                || x.checkout.lfs != dep.checkout.lfs
+        });
+        if (changed || options_changed) && (dep.checkout.submodules || dep.checkout.lfs) {
+            let git = Self::open_checkout(project, &dep)?;
+            if dep.checkout.submodules {
+                git.submodule_update()?;
+            }
+            if dep.checkout.lfs {
+                git.lfs_pull()?;
+            }
+        }
+        Ok(changed)
+    }
 
-            actual_list.push(dep.clone());
-            Self::store_package_list(path.clone(), actual_list);
-            return dep;
+    /// Checks out the locked commit, fetching it only if it is missing. Sparse
+    /// paths are applied before the checkout, so other folders of the package
+    /// are never written.
+    fn checkout_commit(project: &Project, dep: &PackageDependency) -> Result<bool, GitError> {
+        if !Self::has_checkout(project, &dep.name) {
+            let package_dir = std::path::absolute(project.package_dir(&dep.name))
+                .expect("Cannot resolve the package dir");
+            if package_dir.join(".git").exists() {
+                // Keep the files of the stale checkout, they may contain changes.
+                // Earlier backups are kept as well.
+                let folder = package_dir
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                let stale_dir = (1..)
+                    .map(|i| match i {
+                        1 => format!("{}.stale", folder),
+                        _ => format!("{}.stale-{}", folder, i),
+                    })
+                    .map(|x| package_dir.with_file_name(x))
+                    .find(|x| !x.exists())
+                    .unwrap();
+                std::fs::rename(&package_dir, &stale_dir)
+                    .expect("Cannot move the stale checkout aside");
+                println!(
+                    "  The checkout of {} lost its link to the package cache, moved it to {}",
+                    dep.name,
+                    stale_dir.display()
+                );
+            }
+
+            // New checkouts are worktrees of the shared cache, so the objects
+            // are only stored once for all projects
+            Self::fetch_dependency(project, dep)?;
+            let _ = std::fs::create_dir_all(project.cache_dir());
+            let sparse = !dep.checkout.sparse.is_empty();
+            cache::open(&dep.uri, project.package_credentials(&dep.uri))?.worktree_add(
+                cache::worktree_name(&package_dir),
+                package_dir.to_str().unwrap().to_string(),
+                dep.commit_hash.clone(),
+                !sparse,
+            )?;
+
+            if sparse {
+                let git = Self::open_checkout(project, dep)?;
+                git.sparse_checkout(dep.checkout.sparse.clone())?;
+                git.reset(dep.commit_hash.clone())?;
+            }
+            return Ok(true);
+        }
+
+        let git = Self::open_checkout(project, dep)?;
+        if git.sparse_paths()? != dep.checkout.sparse {
+            git.sparse_checkout(dep.checkout.sparse.clone())?;
+        }
+        if git
+            .rev_parse("HEAD".to_string())
+            .is_ok_and(|head| head == dep.commit_hash)
+        {
+            return Ok(false);
+        }
+
+        if !git.has_commit(dep.commit_hash.clone()) {
+            git.fetch_commit(dep.commit_hash.clone(), project.fetch_mode)?;
+        }
+        git.checkout(dep.commit_hash.clone())?;
+        Ok(true)
+    }
+
+    /// Fetches the locked commit into the package's checkout or - if there is
+    /// none yet - into the shared cache
+    fn fetch_dependency(project: &Project, dep: &PackageDependency) -> Result<(), GitError> {
+        let git = if Self::has_checkout(project, &dep.name) {
+            Self::open_checkout(project, dep)?
         } else {
-            panic!(
-                "The package {} in version {} is not available in your registries ",
-                package_name, package_version
-            );
+            cache::open(&dep.uri, project.package_credentials(&dep.uri))?
+        };
+
+        if git.has_commit(dep.commit_hash.clone()) {
+            return Ok(());
+        }
+        git.fetch_commit(dep.commit_hash.clone(), project.fetch_mode)
+    }
+
+    /// Returns a client for the package's existing checkout, which must point
+    /// to the package's URI
+    fn open_checkout(
+        project: &Project,
+        dep: &PackageDependency,
+    ) -> Result<git::GitClient, GitError> {
+        let package_dir = project.package_dir(&dep.name);
+        let git = git::GitClient::create()
+            .cwd(package_dir.to_str().unwrap().to_string())
+            .silent()
+            .credentials(project.package_credentials(&dep.uri));
+
+        let origin = git.remote_url()?;
+        if origin != dep.uri {
+            return Err(GitError {
+                subcommand: "remote".to_string(),
+                status: None,
+                stderr: format!(
+                    "the checkout points to {} instead of {}, remove {} to install the package again",
+                    origin,
+                    dep.uri,
+                    package_dir.display()
+                ),
+                cwd: package_dir.to_str().unwrap().to_string(),
+                auth_failure: false,
+            });
+        }
+        Ok(git)
+    }
+}
+
+/// Provides the packages of the project's registries to the resolver. In
+/// --locked and --frozen mode only the locked versions are offered, otherwise
+/// they are offered in addition to the registries' versions.
+struct RegistrySource<'a> {
+    project: &'a Project,
+    locked: &'a [PackageDependency],
+    mode: InstallMode,
+}
+
+impl PackageSource for RegistrySource<'_> {
+    fn versions(&mut self, name: &str) -> Vec<PackageDependency> {
+        let locked: Vec<PackageDependency> = self
+            .locked
+            .iter()
+            .filter(|x| x.name == name)
+            .cloned()
+            .collect();
+        if self.mode != InstallMode::Normal {
+            return locked;
+        }
+
+        let mut versions: Vec<PackageDependency> = self
+            .project
+            .package_versions(name.to_string())
+            .into_iter()
+            .map(|(version, uri)| PackageDependency {
+                name: name.to_string(),
+                version: version.id,
+                uri,
+                commit_hash: version.commit_hash,
+                checkout: version.checkout,
+            })
+            .collect();
+
+        // A locked version stays usable if it was removed from the index or its
+        // registry is gone, the registry's entry wins if it still lists it
+        for dep in locked.into_iter().rev() {
+            match versions
+                .iter()
+                .position(|x| x.commit_hash == dep.commit_hash)
+            {
+                Some(pos) => {
+                    let listed = versions.remove(pos);
+                    versions.insert(0, listed);
+                }
+                None => versions.insert(0, dep),
+            }
+        }
+        versions
+    }
+
+    fn dependencies(
+        &mut self,
+        package: &PackageDependency,
+    ) -> Result<Vec<PackageRequest>, ResolveError> {
+        let unavailable = format!("{} @ {}", package.name, package.version);
+        if !PackageDependency::is_available_offline(self.project, package) {
+            if self.mode == InstallMode::Frozen {
+                return Err(ResolveError::Unavailable(unavailable));
+            }
+            PackageDependency::fetch_dependency(self.project, package)
+                .map_err(|e| ResolveError::Unavailable(format!("{}: {}", unavailable, e)))?;
         }
+
+        let content = PackageDependency::find_repository(self.project, package)
+            .ok_or_else(|| ResolveError::Unavailable(unavailable.clone()))?
+            .show(
+                package.commit_hash.clone(),
+                GRACE_PACKAGE_FILE_NAME.to_string(),
+            )
+            .map_err(|e| ResolveError::Unavailable(format!("{}: {}", unavailable, e)))?;
+
+        // A package without grace-packages.txt has no dependencies
+        content
+            .unwrap_or_default()
+            .lines()
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| {
+                PackageRequest::parse(x).map_err(|e| {
+                    ResolveError::InvalidPackage(format!(
+                        "The {} of {} is invalid: {}",
+                        GRACE_PACKAGE_FILE_NAME, unavailable, e
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{
+        CheckoutOptions, InstallMode, Package, PackageDependency, PackageList, PackageRequest,
+        PackageVersion, RegistrySource,
+    };
+    use crate::grace::project::{Project, REGISTRY_INDEX_FILE_NAME};
+    use crate::grace::resolver::{PackageSource, ResolveError, Resolver};
+    use crate::grace::Registry;
+
+    /// Offers the versions of a RegistrySource, without fetching any packages
+    /// for their dependencies
+    struct IndexOnly<'a>(RegistrySource<'a>);
+
+    impl PackageSource for IndexOnly<'_> {
+        fn versions(&mut self, name: &str) -> Vec<PackageDependency> {
+            self.0.versions(name)
+        }
+
+        fn dependencies(
+            &mut self,
+            _package: &PackageDependency,
+        ) -> Result<Vec<PackageRequest>, ResolveError> {
+            Ok(vec![])
+        }
+    }
+
+    /// Creates a project in a temporary folder whose registry lists the given
+    /// versions of APackage
+    fn project_with_index(name: &str, versions: &[&str]) -> (PathBuf, Project) {
+        let dir = std::env::temp_dir().join(format!("grace-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.clone());
+        std::fs::create_dir_all(dir.clone()).unwrap();
+
+        let mut project = Project::init(dir.clone());
+        project.registries.push(Registry {
+            uri: "https://example.com/registry".to_string(),
+            branch: None,
+            auth: None,
+        });
+
+        let index = PackageList {
+            packagelist: vec![Package {
+                name: "APackage".to_string(),
+                uri: "https://example.com/a".to_string(),
+                versions: versions
+                    .iter()
+                    .map(|v| PackageVersion {
+                        id: v.to_string(),
+                        commit_hash: format!("APackage-{}", v),
+                        checkout: CheckoutOptions::default(),
+                    })
+                    .collect(),
+            }],
+            ..Default::default()
+        };
+        let registry_dir = project.registry_dir(&project.registries[0]);
+        std::fs::create_dir_all(registry_dir.clone()).unwrap();
+        std::fs::write(
+            registry_dir.join(REGISTRY_INDEX_FILE_NAME),
+            serde_json::to_string(&index).unwrap(),
+        )
+        .unwrap();
+
+        (dir, project)
+    }
+
+    fn locked(version: &str) -> PackageDependency {
+        PackageDependency {
+            name: "APackage".to_string(),
+            version: version.to_string(),
+            uri: "https://example.com/a".to_string(),
+            commit_hash: format!("APackage-{}", version),
+            checkout: CheckoutOptions::default(),
+        }
+    }
+
+    fn resolve(project: &Project, locked: Vec<PackageDependency>, request: &str) -> String {
+        let mut source = IndexOnly(RegistrySource {
+            project,
+            locked: &locked,
+            mode: InstallMode::Normal,
+        });
+        let resolved = Resolver::new(&mut source, locked.clone())
+            .resolve(vec![PackageRequest::from_string(request.to_string())])
+            .unwrap();
+        resolved[0].version.clone()
+    }
+
+    #[test]
+    fn install_keeps_locked_version() {
+        let (dir, project) = project_with_index("keep-locked", &["1.0.0", "1.1.0"]);
+
+        assert_eq!(resolve(&project, vec![], "APackage >=1.0.0"), "1.1.0");
+        assert_eq!(
+            resolve(&project, vec![locked("1.0.0")], "APackage >=1.0.0"),
+            "1.0.0"
+        );
+        // a lock that no longer satisfies the request is replaced
+        assert_eq!(
+            resolve(&project, vec![locked("1.0.0")], "APackage >=1.1.0"),
+            "1.1.0"
+        );
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn install_keeps_locked_version_missing_from_index() {
+        let (dir, project) = project_with_index("missing-locked", &["1.1.0"]);
+
+        assert_eq!(
+            resolve(&project, vec![locked("1.0.0")], "APackage >=1.0.0"),
+            "1.0.0"
+        );
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn update_never_downgrades() {
+        let kept = PackageDependency::keep_unless_newer(&[locked("1.2.0")], vec![locked("1.1.0")]);
+        assert_eq!(kept[0].version, "1.2.0");
+
+        let updated =
+            PackageDependency::keep_unless_newer(&[locked("1.2.0")], vec![locked("1.3.0")]);
+        assert_eq!(updated[0].version, "1.3.0");
+    }
+
+    #[test]
+    fn locked_options_must_match_requested() {
+        let mut requested = locked("1.0.0");
+        assert!(
+            PackageDependency::changed_options(&[locked("1.0.0")], &[requested.clone()]).is_empty()
+        );
+
+        requested.checkout.submodules = true;
+        assert_eq!(
+            PackageDependency::changed_options(&[locked("1.0.0")], &[requested]),
+            vec!["APackage is locked with [] instead of [submodules]".to_string()]
+        );
+    }
+
+    #[test]
+    fn index_skips_invalid_entries() {
+        let (list, skipped) = PackageList::parse(
+            r#"{"packagelist": [
+                {"name": "A", "uri": "https://example.com/a", "versions": [
+                    {"id": "1.0.0", "commit_hash": "abc"},
+                    {"id": "1.1.0"}
+                ]},
+                {"name": "B"},
+                {"name": "C", "uri": "https://example.com/c", "versions": []}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(list.schema_version, 1);
+        let names: Vec<&str> = list.packagelist.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["A", "C"]);
+        assert_eq!(list.packagelist[0].versions.len(), 1);
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0].starts_with("a version of A: missing field `commit_hash`"));
+        assert!(skipped[1].starts_with("package #2: missing field `uri`"));
+
+        assert!(PackageList::parse(r#"{"packages": []}"#).is_err());
+    }
+
+    #[test]
+    fn request_with_invalid_line() {
+        assert!(PackageRequest::parse("APackage").is_err());
+        assert!(PackageRequest::parse("APackage >=1.0 [lfs").is_err());
+        assert!(PackageRequest::parse("APackage >=x").is_err());
+    }
+
+    #[test]
+    fn request_without_options() {
+        let request = PackageRequest::from_string("APackage >=1.2, <1.5".to_string());
+        assert_eq!(request.name, "APackage");
+        assert_eq!(request.checkout, CheckoutOptions::default());
+        assert_eq!(request.to_string(), "APackage >=1.2, <1.5");
+    }
+
+    #[test]
+    fn request_with_options() {
+        let request = PackageRequest::from_string("APackage >=1.2 [submodules, lfs]".to_string());
+        assert_eq!(request.requirement.to_string(), ">=1.2");
+        assert!(request.checkout.submodules);
+        assert!(request.checkout.lfs);
+        assert_eq!(request.to_string(), "APackage >=1.2 [submodules, lfs]");
+
+        let request = PackageRequest::from_string("APackage 1.0.0 [lfs]".to_string());
+        assert!(!request.checkout.submodules);
+        assert!(request.checkout.lfs);
+    }
+
+    #[test]
+    fn request_with_sparse_paths() {
+        let request = PackageRequest::from_string(
+            "APackage 1.0.0 [sparse=src/lib/, sparse=/include]".to_string(),
+        );
+        assert_eq!(request.checkout.sparse, vec!["src/lib", "include"]);
+        assert_eq!(
+            request.to_string(),
+            "APackage ^1.0.0 [sparse=src/lib, sparse=include]"
+        );
+
+        let merged = request.checkout.merge(
+            PackageRequest::from_string(
+                "APackage 1.0.0 [lfs, sparse=include, sparse=doc]".to_string(),
+            )
+            .checkout,
+        );
+        assert!(merged.lfs);
+        assert_eq!(merged.sparse, vec!["src/lib", "include", "doc"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown option sparse")]
+    fn request_with_unknown_option() {
+        PackageRequest::from_string("APackage 1.0.0 [sparse]".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid sparse path")]
+    fn request_with_invalid_sparse_path() {
+        PackageRequest::from_string("APackage 1.0.0 [sparse=../other]".to_string());
     }
 }
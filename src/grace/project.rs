@@ -1,19 +1,34 @@
 use crate::grace::package::PackageList;
 use crate::grace::semver::SemanticVersion;
-use std::fs::{File, OpenOptions};
+use std::cell::RefCell;
+use std::fs::File;
 use std::io::prelude::*;
 use std::{io::BufReader, path::PathBuf};
 
-use super::package::{Package, PackageVersion, VersionSelector};
-use super::{git, Registry};
+use super::git::{Credentials, FetchMode, GitError};
+use super::package::{CheckoutOptions, Package, PackageDependency, PackageVersion};
+use super::{cache, git, Registry};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Project {
     pub registries: Vec<Registry>,
 
+    /// How package commits are fetched, see FetchMode
+    #[serde(default)]
+    pub fetch_mode: FetchMode,
+
+    /// The folder packages are checked out to, relative to the project root
+    #[serde(default = "default_cache_dir", alias = "cache-dir")]
+    pub cache_dir: String,
+
     #[serde(skip)]
     project_dir: PathBuf,
+
+    /// Warnings that were printed already, indexes are read many times per
+    /// run but each problem is only reported once
+    #[serde(skip)]
+    warnings: RefCell<Vec<String>>,
 }
 
 // This folder is used to holde checked out registry data.
@@ -21,16 +36,99 @@ pub const GRACE_ROOT_FOLDER: &str = ".grace";
 pub const GRACE_PROJECT_FILE_NAME: &str = "grace-config.json";
 pub const GRACE_PACKAGE_FILE_NAME: &str = "grace-packages.txt";
 pub const GRACE_PACKAGE_LOCK_FILE_NAME: &str = "grace-lock.json";
+pub const REGISTRY_INDEX_FILE_NAME: &str = "index.json";
+// Version of the index.json format written by this version of grace
+pub const REGISTRY_SCHEMA_VERSION: u32 = 1;
+pub const DEFAULT_CACHE_DIR: &str = "packages";
+
+// Environment variable that overrides the cache_dir of the project
+pub const GRACE_CACHE_DIR_ENV: &str = "GRACE_CACHE_DIR";
+
+// Maximum length of the readable part of a registry's folder name
+const MAX_REGISTRY_PREFIX_LENGTH: usize = 32;
+
+// Number of times publishing is retried if the registry changed concurrently
+const MAX_PUBLISH_ATTEMPTS: u32 = 5;
+
+fn default_cache_dir() -> String {
+    DEFAULT_CACHE_DIR.to_string()
+}
 
 impl Project {
-    fn uri_to_directory(uri: String) -> String {
-        let reg_dir = uri
-            .clone()
-            .replace(":", "_")
-            .replace("/", "_")
-            .replace("\\", "_");
-
-        reg_dir
+    /// Returns the folder name of a registry's index. It starts with the last
+    /// part of the URI for readability, the hash keeps URIs that only differ
+    /// in special characters apart.
+    fn uri_to_directory(uri: &str) -> String {
+        let uri = Self::normalize_uri(uri);
+        let name = uri.rsplit(['/', '\\', ':']).next().unwrap_or_default();
+        let name = name.strip_suffix(".git").unwrap_or(name);
+        let prefix: String = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .take(MAX_REGISTRY_PREFIX_LENGTH)
+            .collect();
+
+        if prefix.is_empty() {
+            cache::short_hash(&uri)
+        } else {
+            format!("{}-{}", prefix, cache::short_hash(&uri))
+        }
+    }
+
+    /// The folder name used for a registry's index by earlier versions of grace
+    fn legacy_uri_to_directory(uri: &str) -> String {
+        uri.replace([':', '/', '\\'], "_")
+    }
+
+    /// Moves indexes that were checked out by earlier versions of grace to
+    /// their current folder. An index is only moved if it was cloned from the
+    /// registry, as different URIs could share the same legacy folder.
+    fn migrate_registry_dirs(&self) {
+        for r in self.registries.iter() {
+            let registry_dir = self.registry_dir(r);
+            let mut legacy_dir = self.project_dir.clone();
+            legacy_dir.push(GRACE_ROOT_FOLDER);
+            legacy_dir.push(Self::legacy_uri_to_directory(&r.uri));
+            if registry_dir.exists() || !legacy_dir.exists() {
+                continue;
+            }
+
+            let origin = git::GitClient::create()
+                .cwd(legacy_dir.to_str().unwrap().to_string())
+                .silent()
+                .remote_url();
+            if origin.is_ok_and(|x| Self::normalize_uri(&x) == Self::normalize_uri(&r.uri)) {
+                println!(
+                    "Moving the index of {} to {}",
+                    r.uri,
+                    registry_dir.display()
+                );
+                if let Err(e) = std::fs::rename(legacy_dir, registry_dir) {
+                    println!("..failed: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Brings a registry URI into the form stored in the config, so the same
+    /// registry is not added twice with a slightly different spelling
+    pub fn normalize_uri(uri: &str) -> String {
+        let uri = uri.trim();
+        match uri.trim_end_matches('/') {
+            "" => uri.to_string(),
+            trimmed => trimmed.to_string(),
+        }
+    }
+
+    /// Returns the registry with the given URI
+    fn find_registry(&self, uri: &str) -> Option<&Registry> {
+        let uri = Self::normalize_uri(uri);
+        self.registries
+            .iter()
+            .find(|x| Self::normalize_uri(&x.uri) == uri)
     }
 
     pub fn init(path: PathBuf) -> Self {
@@ -42,21 +140,16 @@ impl Project {
 
         std::fs::create_dir(grace_dir.clone()).expect("Cannot create .grace dir");
 
-        let mut package_dir = path.clone();
-        package_dir.push("packages");
-        std::fs::create_dir(package_dir.clone()).expect("Cannot create package dir");
-
-        let result = Self {
+        let mut result = Self {
             registries: vec![],
+            fetch_mode: FetchMode::default(),
+            cache_dir: default_cache_dir(),
             project_dir: path.clone(),
+            warnings: RefCell::default(),
         };
+        std::fs::create_dir_all(result.cache_dir()).expect("Cannot create package dir");
 
-        let mut cfg_file = grace_dir.clone();
-        cfg_file.push(GRACE_PROJECT_FILE_NAME);
-        let mut file =
-            File::create(cfg_file.to_str().unwrap()).expect("Failed to create config file");
-        let _ = file.write_all(serde_json::to_string(&result).unwrap().as_bytes());
-
+        result.store();
         result
     }
 
@@ -75,196 +168,627 @@ impl Project {
         let mut data: Project =
             serde_json::from_reader(reader).expect("Project configuration is corrupt");
         data.project_dir = path.clone();
+        data.migrate_registry_dirs();
         data
     }
 
-    pub fn add_registry(mut self, registry: String) {
-        let r = Registry { uri: registry };
-        self.registries.push(r.clone());
-        self.update_registry(&r);
+    /// Adds a registry to the project. The registry is only saved if its index
+    /// could be checked out.
+    pub fn add_registry(mut self, mut r: Registry) -> Result<(), String> {
+        r.uri = Self::normalize_uri(&r.uri);
+        if self.find_registry(&r.uri).is_some() {
+            println!("The registry {} is already used by this project.", r.uri);
+            return Ok(());
+        }
+
+        if let Err(e) = self.update_registry(&r) {
+            return Err(format!("..failed: {}", e));
+        }
+
+        let mut index_file = self.registry_dir(&r);
+        index_file.push(REGISTRY_INDEX_FILE_NAME);
+        if !index_file.exists() {
+            let _ = std::fs::remove_dir_all(self.registry_dir(&r));
+            return Err(format!(
+                "..failed: {} does not contain an {}, is this a registry?",
+                r.uri, REGISTRY_INDEX_FILE_NAME
+            ));
+        }
+
+        self.registries.push(r);
+        self.store();
+        Ok(())
+    }
+
+    /// Removes the registry and its checked out index. Packages that were
+    /// installed from the registry stay in place.
+    pub fn remove_registry(mut self, uri: String) -> Result<(), String> {
+        let registry = match self.find_registry(&uri) {
+            Some(r) => r.clone(),
+            None => return Err(format!("The registry {} is not used by this project.", uri)),
+        };
+
+        if let Some(packages) = self.fetch_packages(&registry) {
+            let locked: Vec<PackageDependency> =
+                PackageDependency::get_package_list(self.project_dir.clone())
+                    .into_iter()
+                    .filter(|dep| {
+                        packages
+                            .packagelist
+                            .iter()
+                            .any(|x| x.name == dep.name && x.uri == dep.uri)
+                    })
+                    .collect();
+            for dep in locked.iter() {
+                println!(
+                    "Warning: {} {} was installed from {}, it stays in {} but cannot be updated anymore.",
+                    dep.name, dep.version, registry.uri, GRACE_PACKAGE_LOCK_FILE_NAME
+                );
+            }
+        }
+
+        self.registries.retain(|x| x.uri != registry.uri);
+        self.store();
+
+        let registry_dir = self.registry_dir(&registry);
+        if registry_dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(registry_dir.clone()) {
+                println!("..failed to delete {}: {}", registry_dir.display(), e);
+            }
+        }
+        println!("Removed registry {}", registry.uri);
+        Ok(())
+    }
+
+    /// Writes the configuration to grace-config.json. The file is replaced in
+    /// one step, so an interrupted write never leaves a broken config behind.
+    fn store(&mut self) {
+        let mut seen = vec![];
+        self.registries.retain_mut(|r| {
+            r.uri = Self::normalize_uri(&r.uri);
+            if seen.contains(&r.uri) {
+                return false;
+            }
+            seen.push(r.uri.clone());
+            true
+        });
 
         let mut cfg_file = self.project_dir.clone();
         cfg_file.push(GRACE_ROOT_FOLDER);
         cfg_file.push(GRACE_PROJECT_FILE_NAME);
-        let mut file = OpenOptions::new()
-            .write(true)
-            .open(cfg_file)
-            .expect("Failed to open config file");
-        let _ = file.write_all(serde_json::to_string(&self).unwrap().as_bytes());
+        let tmp_file = cfg_file.with_extension("json.tmp");
+
+        let mut file = File::create(tmp_file.clone()).expect("Failed to create config file");
+        file.write_all(serde_json::to_string(&self).unwrap().as_bytes())
+            .and_then(|_| file.sync_all())
+            .expect("Failed to write config file");
+        std::fs::rename(tmp_file, cfg_file).expect("Failed to replace config file");
     }
 
     pub fn update_registries(&self) {
         // checkout all registries
         for r in self.registries.iter() {
-            self.update_registry(r);
+            if let Err(e) = self.update_registry(r) {
+                println!("..failed: {}", e);
+            }
         }
     }
 
-    fn update_registry(&self, r: &Registry) {
+    fn update_registry(&self, r: &Registry) -> Result<(), GitError> {
         println!("updating registry {}", r.uri.clone());
 
-        let mut registry_dir = self.project_dir.clone();
-        registry_dir.push(GRACE_ROOT_FOLDER);
-
-        // sanitize path:
-        let reg_dir = Self::uri_to_directory(r.uri.clone());
-
-        registry_dir.push(reg_dir);
+        let registry_dir = self.registry_dir(r);
 
         if !registry_dir.exists() {
             println!("this is a new registry.");
             let _ = std::fs::create_dir(registry_dir.clone());
             let git = git::GitClient::create();
-            git.cwd(registry_dir.clone().to_str().unwrap().to_string())
-                .clone(r.uri.clone(), true);
+            let cloned = git
+                .cwd(registry_dir.clone().to_str().unwrap().to_string())
+                .credentials(r.auth.clone())
+                .clone(r.uri.clone(), true, r.branch.clone());
+            if cloned.is_err() {
+                // don't leave an empty folder behind that looks like a checkout
+                let _ = std::fs::remove_dir_all(registry_dir);
+            }
+            return cloned;
         }
 
-        let git = git::GitClient::create();
-        let ok = git.cwd(registry_dir.to_str().unwrap().to_string())
+        let git = git::GitClient::create()
+            .cwd(registry_dir.to_str().unwrap().to_string())
             .silent()
-            .init()
-            .remote(r.uri.clone())
-            .pull()
-            .fetch()
-            .err();
-
-        if !ok {
-            println!("..failed.")
+            .credentials(r.auth.clone());
+        git.pull(self.registry_branch(r, &git)?)
+    }
+
+    /// Prints all registries of the project along with the state of their
+    /// checked out index
+    pub fn list_registries(&self) {
+        if self.registries.is_empty() {
+            println!("This project uses no registries, add one with 'grace registry add'.");
+            return;
+        }
+
+        for r in self.registries.iter() {
+            let registry_dir = self.registry_dir(r);
+            println!("{}", r.uri);
+            println!("  index:    {}", registry_dir.display());
+            match Self::last_update(&registry_dir) {
+                Some(time) => println!("  updated:  {}", time),
+                None => println!("  updated:  never, run 'grace registry update'"),
+            }
+            match self.fetch_packages(r) {
+                Some(packages) => println!("  packages: {}", packages.packagelist.len()),
+                None => println!("  packages: unknown"),
+            }
         }
     }
 
-    fn fetch_packages(&self, registry: &Registry) -> Option<PackageList> {
-        let mut registry_dir = self.project_dir.clone();
-        registry_dir.push(GRACE_ROOT_FOLDER);
-        registry_dir.push(Self::uri_to_directory(registry.uri.clone()));
+    /// Prints the checked out commit of a registry's index and all packages it
+    /// offers
+    pub fn registry_info(&self, uri: String) -> Result<(), String> {
+        let registry = match self.find_registry(&uri) {
+            Some(r) => r,
+            None => return Err(format!("The registry {} is not used by this project.", uri)),
+        };
 
+        let registry_dir = self.registry_dir(registry);
         if !registry_dir.exists() {
-            return None;
+            println!(
+                "The index of {} is not checked out, run 'grace registry update'.",
+                uri
+            );
+            return Ok(());
         }
 
-        registry_dir.push("index.json");
-        let file = File::open(registry_dir);
+        let git = git::GitClient::create()
+            .cwd(registry_dir.to_str().unwrap().to_string())
+            .silent();
+        println!("{}", uri);
+        if let Some(branch) = registry.branch.as_ref() {
+            println!("  branch: {}", branch);
+        }
+        match git.rev_parse("HEAD".to_string()) {
+            Ok(commit) => println!("  commit: {}", commit),
+            Err(e) => println!("  commit: unknown ({})", e),
+        }
 
-        if file.is_err() {
-            return None;
+        let packages = self.fetch_packages(registry).unwrap_or_default();
+        if packages.packagelist.is_empty() {
+            println!("  no packages");
         }
+        for package in packages.packagelist.iter() {
+            let versions: Vec<&str> = package.versions.iter().map(|x| x.id.as_str()).collect();
+            println!("  {} ({})", package.name, package.uri);
+            if versions.is_empty() {
+                println!("    versions: none");
+            } else {
+                println!("    versions: {}", versions.join(", "));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how long ago the index in registry_dir was cloned or pulled
+    fn last_update(registry_dir: &std::path::Path) -> Option<String> {
+        // FETCH_HEAD is written by every pull, HEAD by the initial clone
+        let modified = ["FETCH_HEAD", "HEAD"]
+            .iter()
+            .filter_map(|x| std::fs::metadata(registry_dir.join(".git").join(x)).ok())
+            .filter_map(|x| x.modified().ok())
+            .max()?;
+
+        let seconds = modified.elapsed().map(|x| x.as_secs()).unwrap_or(0);
+        Some(match seconds {
+            0..=59 => "just now".to_string(),
+            60..=3599 => format!("{} minutes ago", seconds / 60),
+            3600..=86399 => format!("{} hours ago", seconds / 3600),
+            _ => format!("{} days ago", seconds / 86400),
+        })
+    }
+
+    /// Returns the branch of the registry that holds the index. This is either
+    /// the branch configured for the registry or the remote's default branch.
+    fn registry_branch(&self, r: &Registry, git: &git::GitClient) -> Result<String, GitError> {
+        match r.branch.as_ref() {
+            Some(branch) => Ok(branch.clone()),
+            None => git.default_branch(),
+        }
+    }
+
+    /// Returns the folder packages are checked out to. GRACE_CACHE_DIR takes
+    /// precedence over the configured cache_dir, relative paths are resolved
+    /// against the project root.
+    pub fn cache_dir(&self) -> PathBuf {
+        let cache_dir = match std::env::var(GRACE_CACHE_DIR_ENV) {
+            Ok(dir) if !dir.is_empty() => dir,
+            _ => self.cache_dir.clone(),
+        };
+
+        let mut dir = self.project_dir.clone();
+        dir.push(cache_dir);
+        dir
+    }
+
+    /// Returns the folder a package is checked out to
+    pub fn package_dir(&self, package_name: &str) -> PathBuf {
+        let mut dir = self.cache_dir();
+        dir.push(package_name);
+        dir
+    }
 
-        let reader = BufReader::new(file.unwrap());
-        let packages: PackageList = serde_json::from_reader(reader).expect("Malformed index.json");
+    /// Returns the folder the registry's index is checked out to
+    pub(crate) fn registry_dir(&self, registry: &Registry) -> PathBuf {
+        let mut registry_dir = self.project_dir.clone();
+        registry_dir.push(GRACE_ROOT_FOLDER);
+        registry_dir.push(Self::uri_to_directory(&registry.uri));
+        registry_dir
+    }
 
-        return Some(packages);
+    /// Prints a warning unless it was printed before
+    fn warn(&self, warning: String) {
+        if !self.warnings.borrow().contains(&warning) {
+            println!("  Warning: {}", warning);
+            self.warnings.borrow_mut().push(warning);
+        }
     }
 
-    pub(crate) fn resolve_package(
+    /// Reads the registry's index along with a description of each entry that
+    /// was skipped. Returns None if the index is not checked out and an error
+    /// if it is not a package list at all.
+    fn read_index(
         &self,
-        package_name: String,
-        package_version: SemanticVersion,
-        selector: VersionSelector,
-    ) -> Option<(PackageVersion, String)> {
-        println!("  Checking registries for package {}", package_name);
+        registry: &Registry,
+    ) -> Result<Option<(PackageList, Vec<String>)>, String> {
+        let mut index_file = self.registry_dir(registry);
+        index_file.push(REGISTRY_INDEX_FILE_NAME);
+
+        let data = match std::fs::read_to_string(index_file) {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
 
-        let mut found_package: Option<(PackageVersion, String)> = None;
-        let sought_version = package_version;
+        let (packages, skipped) = PackageList::parse(&data)?;
+        if packages.schema_version > REGISTRY_SCHEMA_VERSION {
+            self.warn(format!(
+                "the index of {} was written by a newer version of grace, please update grace.",
+                registry.uri
+            ));
+        }
+        Ok(Some((packages, skipped)))
+    }
 
-        for r in self.registries.iter() {
-            println!("  ...{}", r.uri);
+    /// Returns the packages of the registry. Invalid entries are reported and
+    /// skipped, a malformed index is treated like a missing one.
+    fn fetch_packages(&self, registry: &Registry) -> Option<PackageList> {
+        match self.read_index(registry) {
+            Ok(Some((packages, skipped))) => {
+                for entry in skipped {
+                    self.warn(format!(
+                        "skipping an invalid entry in the index of {}: {}",
+                        registry.uri, entry
+                    ));
+                }
+                Some(packages)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.warn(format!(
+                    "ignoring the index of {}, it is malformed: {}",
+                    registry.uri, e
+                ));
+                None
+            }
+        }
+    }
 
-            if let Some(packages) = self.fetch_packages(r) {
-                for package in packages
-                    .packagelist
-                    .iter()
-                    .filter(|x| x.name == package_name)
-                {
-                    for version in package.versions.iter() {
-                        if let None = found_package {
-                            // this should only ever happen if the found package's version is actually
-                            // compatible to the one we've passed in
-                            if is_usable_for(&version.as_semver(), &sought_version, selector) {
-                                found_package = Some((version.clone(), package.uri.clone()));
-                            }
-                        }
+    fn store_packages(&self, registry: &Registry, packages: &PackageList) -> Result<(), String> {
+        let mut index_file = self.registry_dir(registry);
+        index_file.push(REGISTRY_INDEX_FILE_NAME);
+        File::create(index_file)
+            .and_then(|mut file| {
+                file.write_all(serde_json::to_string_pretty(packages).unwrap().as_bytes())
+            })
+            .map_err(|e| format!("Failed to write {}: {}", REGISTRY_INDEX_FILE_NAME, e))
+    }
 
-                        if let Some(p) = found_package.clone() {
-                            /*
-                                Check if this package version is:
-                                * newer than the previously selected one
-                                * and still compatible with the input version.
-                            */
-                            found_package = select_package(
-                                package,
-                                version,
-                                p.0,
-                                &sought_version,
-                                found_package,
-                                selector,
-                            )
-                        }
-                    }
+    /// Finds the registry a package should be published to. This is either the
+    /// registry that already lists the package or - for new packages - the
+    /// registry given by the user.
+    fn find_publish_registry(
+        &self,
+        package_name: &str,
+        registry_uri: Option<String>,
+    ) -> Result<Registry, String> {
+        for r in self.registries.iter() {
+            if let Some(packages) = self.fetch_packages(r) {
+                if packages.packagelist.iter().any(|x| x.name == package_name) {
+                    return Ok(r.clone());
                 }
             }
         }
-        found_package
+
+        let uri = registry_uri.ok_or_else(|| {
+            format!(
+                "The package {} is not known to any registry, please specify the registry to publish to.",
+                package_name
+            )
+        })?;
+        match self.find_registry(&uri) {
+            Some(r) => Ok(r.clone()),
+            None => Err(format!(
+                "The registry {} is not used by this project, add it with 'grace registry add' first.",
+                uri
+            )),
+        }
     }
-}
 
-fn is_usable_for(
-    version_a: &SemanticVersion,
-    version_b: &SemanticVersion,
-    selector: VersionSelector,
-) -> bool {
-    let compat = version_a.match_to(version_b);
-    match compat {
-        crate::grace::semver::Compatibility::Breaking => return false,
-        crate::grace::semver::Compatibility::Exact => {
-            return true;
+    /// Publishes the current HEAD of a package in the cache dir as a new version
+    /// to the registry it originated from. Fails if the version exists already
+    /// or the commit was not pushed to the package's remote.
+    pub fn publish_package(
+        &self,
+        package_name: String,
+        package_version: SemanticVersion,
+        registry_uri: Option<String>,
+    ) -> Result<(), String> {
+        let package_dir = self.package_dir(&package_name);
+        if !package_dir.exists() {
+            return Err(format!("The package {} is not installed.", package_name));
         }
-        crate::grace::semver::Compatibility::Partial => {
-            if selector == VersionSelector::LargerEquals || selector == VersionSelector::Compatible
-            {
-                return true;
+
+        let package_git = git::GitClient::create().cwd(package_dir.to_str().unwrap().to_string());
+        let commit_hash = package_git
+            .rev_parse("HEAD".to_string())
+            .map_err(|e| format!("Failed to read the commit id of the package: {}", e))?;
+
+        let locked = PackageDependency::get_package_list(self.project_dir.clone())
+            .into_iter()
+            .find(|x| x.name == package_name);
+        if let Some(dep) = locked.as_ref() {
+            if dep.commit_hash == commit_hash {
+                println!(
+                    "{} has not changed since version {}, nothing to publish.",
+                    package_name, dep.version
+                );
+                return Ok(());
             }
         }
-        crate::grace::semver::Compatibility::Compatible => {
-            if selector == VersionSelector::LargerEquals {
-                return true;
+
+        let registry = self.find_publish_registry(&package_name, registry_uri)?;
+
+        // Other machines fetch the commit from the package's remote, so it
+        // must have been pushed there
+        let package_git = package_git.silent().credentials(registry.auth.clone());
+        match package_git.remote_contains(commit_hash.clone()) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(format!(
+                    "The commit {} of {} is not on its remote, push it before publishing.",
+                    commit_hash, package_name
+                ))
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Failed to check if the commit of {} is on its remote: {}",
+                    package_name, e
+                ))
             }
         }
-    }
-    false
-}
 
-fn select_package(
-    package: &Package,
-    new_selected_version: &PackageVersion,
-    last_selected_version: PackageVersion,
-    sought_version: &SemanticVersion,
-    found_package: Option<(PackageVersion, String)>,
-    selector: VersionSelector,
-) -> Option<(PackageVersion, String)> {
-    let found_version = new_selected_version.as_semver();
-    let current_version = last_selected_version.as_semver();
-    if current_version < found_version {
-        let compat = sought_version.match_to(&found_version);
-
-        match compat {
-            crate::grace::semver::Compatibility::Breaking => return found_package,
-            crate::grace::semver::Compatibility::Exact => {
-                return Some((new_selected_version.clone(), package.uri.clone()));
+        if !self.registry_dir(&registry).exists() {
+            self.update_registry(&registry)
+                .map_err(|e| format!("Failed to clone registry {}: {}", registry.uri, e))?;
+        }
+        let registry_git = git::GitClient::create()
+            .cwd(self.registry_dir(&registry).to_str().unwrap().to_string())
+            .silent()
+            .credentials(registry.auth.clone());
+        let branch = self
+            .registry_branch(&registry, &registry_git)
+            .map_err(|e| format!("Failed to query registry {}: {}", registry.uri, e))?;
+
+        for attempt in 1..=MAX_PUBLISH_ATTEMPTS {
+            println!(
+                "Publishing {} {} to {} (attempt {})",
+                package_name, package_version, registry.uri, attempt
+            );
+
+            // Always start from the current state of the remote index, this
+            // also discards the commit of a previously rejected attempt.
+            registry_git
+                .fetch()
+                .and_then(|_| registry_git.reset(format!("origin/{}", branch)))
+                .map_err(|e| format!("Failed to update registry {}: {}", registry.uri, e))?;
+
+            // Entries that cannot be read would be lost when the index is
+            // written back, so these have to be fixed by hand first
+            let mut packages = match self.read_index(&registry) {
+                Ok(Some((packages, skipped))) if skipped.is_empty() => packages,
+                Ok(Some((_, skipped))) => {
+                    return Err(format!(
+                    "The index of {} contains invalid entries, fix them before publishing:\n  {}",
+                    registry.uri,
+                    skipped.join("\n  ")
+                ))
+                }
+                Ok(None) => PackageList::default(),
+                Err(e) => return Err(format!("The index of {} is malformed: {}", registry.uri, e)),
+            };
+
+            let new_version = PackageVersion {
+                id: package_version.to_string(),
+                commit_hash: commit_hash.clone(),
+                checkout: CheckoutOptions::default(),
+            };
+
+            match packages
+                .packagelist
+                .iter_mut()
+                .find(|x| x.name == package_name)
+            {
+                Some(package) => {
+                    if package
+                        .versions
+                        .iter()
+                        .any(|x| x.as_semver().is_ok_and(|v| v == package_version))
+                    {
+                        return Err(format!(
+                            "Version {} of {} already exists in {}, aborting.",
+                            package_version, package_name, registry.uri
+                        ));
+                    }
+                    package.versions.push(new_version);
+                }
+                None => {
+                    let uri = match locked.as_ref() {
+                        Some(dep) => dep.uri.clone(),
+                        None => package_git
+                            .remote_url()
+                            .map_err(|e| format!("Failed to read the URI of the package: {}", e))?,
+                    };
+                    packages.packagelist.push(Package {
+                        name: package_name.clone(),
+                        uri,
+                        versions: vec![new_version],
+                    });
+                }
             }
-            crate::grace::semver::Compatibility::Partial => {
-                if selector == VersionSelector::LargerEquals
-                    || selector == VersionSelector::Compatible
-                {
-                    return Some((new_selected_version.clone(), package.uri.clone()));
+
+            self.store_packages(&registry, &packages)?;
+
+            registry_git
+                .add(REGISTRY_INDEX_FILE_NAME.to_string())
+                .and_then(|_| {
+                    registry_git.commit(format!("Publish {} {}", package_name, package_version))
+                })
+                .map_err(|e| format!("Failed to commit to registry {}: {}", registry.uri, e))?;
+
+            // Only a registry that moved on in the meantime is worth another
+            // attempt, all other failures won't go away by retrying
+            match registry_git.push(branch.clone()) {
+                Ok(true) => {
+                    println!("..done.");
+                    return Ok(());
+                }
+                Ok(false) => println!("..the registry changed in the meantime, retrying."),
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to push to registry {}: {}",
+                        registry.uri, e
+                    ))
                 }
             }
-            crate::grace::semver::Compatibility::Compatible => {
-                if selector == VersionSelector::LargerEquals {
-                    return Some((new_selected_version.clone(), package.uri.clone()));
+        }
+
+        Err(format!(
+            "Failed to publish {} after {} attempts.",
+            package_name, MAX_PUBLISH_ATTEMPTS
+        ))
+    }
+
+    /// Returns the credentials of the registry that lists the package at uri
+    pub(crate) fn package_credentials(&self, package_uri: &str) -> Option<Credentials> {
+        self.registries
+            .iter()
+            .filter(|r| r.auth.is_some())
+            .find(|r| {
+                self.fetch_packages(r)
+                    .is_some_and(|p| p.packagelist.iter().any(|x| x.uri == package_uri))
+            })
+            .and_then(|r| r.auth.clone())
+    }
+
+    /// Returns all versions of a package found in the registries along with the
+    /// package's URI
+    pub(crate) fn package_versions(&self, package_name: String) -> Vec<(PackageVersion, String)> {
+        let mut versions = vec![];
+        for r in self.registries.iter() {
+            if let Some(packages) = self.fetch_packages(r) {
+                for package in packages
+                    .packagelist
+                    .into_iter()
+                    .filter(|x| x.name == package_name)
+                {
+                    for version in package.versions {
+                        if let Err(e) = version.as_semver() {
+                            println!(
+                                "  Skipping invalid version of {} in {}: {}",
+                                package.name, r.uri, e
+                            );
+                            continue;
+                        }
+                        versions.push((version, package.uri.clone()));
+                    }
                 }
             }
         }
+        versions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_uri_removes_trailing_slashes() {
+        assert_eq!(
+            Project::normalize_uri(" https://foo.bar/registry/ "),
+            "https://foo.bar/registry"
+        );
+        assert_eq!(Project::normalize_uri("/"), "/");
     }
 
-    found_package
+    #[test]
+    fn registry_dirs_do_not_collide() {
+        let a = Project::uri_to_directory("https://a/b_c");
+        let b = Project::uri_to_directory("https://a_b/c");
+        assert_ne!(a, b);
+        assert!(a.starts_with("b_c-"));
+        assert!(b.starts_with("c-"));
+
+        assert_eq!(
+            Project::uri_to_directory("https://foo.bar/registry.git/"),
+            Project::uri_to_directory("https://foo.bar/registry.git")
+        );
+        assert!(Project::uri_to_directory("https://foo.bar/registry.git").starts_with("registry-"));
+
+        let long = format!("https://foo.bar/{}", "x".repeat(300));
+        assert!(Project::uri_to_directory(&long).len() <= MAX_REGISTRY_PREFIX_LENGTH + 17);
+    }
+
+    #[test]
+    fn store_writes_unique_registries() {
+        let dir = std::env::temp_dir().join(format!("grace-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.clone());
+        std::fs::create_dir_all(dir.clone()).unwrap();
+
+        let mut project = Project::init(dir.clone());
+        let registry = |uri: &str| Registry {
+            uri: uri.to_string(),
+            branch: None,
+            auth: None,
+        };
+        project.registries = vec![
+            registry("https://foo.bar/a/"),
+            registry("https://foo.bar/a"),
+            registry("https://foo.bar/b"),
+        ];
+        project.store();
+
+        let uris: Vec<String> = Project::open(dir.clone())
+            .registries
+            .into_iter()
+            .map(|x| x.uri)
+            .collect();
+        assert_eq!(uris, vec!["https://foo.bar/a", "https://foo.bar/b"]);
+
+        // a shorter config replaces the old one completely
+        project.registries.clear();
+        project.store();
+        assert!(Project::open(dir.clone()).registries.is_empty());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }
@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::io::{prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+
+use super::git;
+use super::package::{Package, PackageList};
+use super::project::REGISTRY_INDEX_FILE_NAME;
+
+const REGISTRY_README_FILE_NAME: &str = "README.md";
+
+const REGISTRY_README: &str = "\
+## Grace registry
+
+This repository is a package registry for [Grace](https://github.com/rincewound/grace-git). It
+only contains index.json, which lists the packages, their URIs and the commits of their versions.
+
+Use it in a project with `grace registry add <URI of this repository>`.
+Add a package with `grace registry add-package <name> <uri>` and publish its versions from a
+project with `grace package publish <name>/<version>`.
+";
+
+/// Creates a new registry at path: a git repository with an empty index and
+/// a README explaining how to use it. The result is committed and ready to
+/// be pushed.
+pub fn init(path: PathBuf) -> Result<(), String> {
+    let index_file = path.join(REGISTRY_INDEX_FILE_NAME);
+    if index_file.exists() {
+        return Err(format!("{} already is a grace registry.", path.display()));
+    }
+
+    std::fs::create_dir_all(path.clone())
+        .map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    let git = git::GitClient::create()
+        .cwd(path.to_str().unwrap().to_string())
+        .silent();
+    if !path.join(".git").exists() {
+        git.init(false)
+            .map_err(|e| format!("Failed to create the registry repository: {}", e))?;
+    }
+
+    let readme_file = path.join(REGISTRY_README_FILE_NAME);
+    let readme_before = std::fs::read(&readme_file).ok();
+    store_index(&path, &PackageList::default())?;
+
+    let mut readme = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&readme_file)
+        .map_err(|e| format!("Failed to open {}: {}", REGISTRY_README_FILE_NAME, e))?;
+    if readme.metadata().is_ok_and(|x| x.len() > 0) {
+        let _ = readme.write_all(b"\n");
+    }
+    let _ = readme.write_all(REGISTRY_README.as_bytes());
+
+    let committed = commit(
+        &git,
+        vec![REGISTRY_INDEX_FILE_NAME, REGISTRY_README_FILE_NAME],
+        "Initialize grace registry".to_string(),
+    );
+    if committed.is_err() {
+        // Leave the folder as it was, so init can simply be run again
+        let _ = std::fs::remove_file(&index_file);
+        let _ = match readme_before {
+            Some(content) => std::fs::write(&readme_file, content),
+            None => std::fs::remove_file(&readme_file),
+        };
+        for file in [REGISTRY_INDEX_FILE_NAME, REGISTRY_README_FILE_NAME] {
+            let _ = git.add(file.to_string());
+        }
+    }
+    committed?;
+
+    println!("Created registry in {}", path.display());
+    Ok(())
+}
+
+/// Adds a package without versions to the registry at path and commits it
+pub fn add_package(path: PathBuf, name: String, uri: String) -> Result<(), String> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("Invalid package name '{}'.", name));
+    }
+
+    let index_file = path.join(REGISTRY_INDEX_FILE_NAME);
+    let file = File::open(index_file).map_err(|_| {
+        format!(
+            "{} is not a grace registry, create one with 'grace registry init'.",
+            path.display()
+        )
+    })?;
+    let mut packages: PackageList = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("Malformed {}: {}", REGISTRY_INDEX_FILE_NAME, e))?;
+
+    if packages.packagelist.iter().any(|x| x.name == name) {
+        return Err(format!("The registry already contains a package {}.", name));
+    }
+    packages.packagelist.push(Package {
+        name: name.clone(),
+        uri,
+        versions: vec![],
+    });
+    store_index(&path, &packages)?;
+
+    let git = git::GitClient::create()
+        .cwd(path.to_str().unwrap().to_string())
+        .silent();
+    commit(
+        &git,
+        vec![REGISTRY_INDEX_FILE_NAME],
+        format!("Add package {}", name),
+    )?;
+    println!("Added package {}", name);
+    Ok(())
+}
+
+fn store_index(path: &Path, packages: &PackageList) -> Result<(), String> {
+    File::create(path.join(REGISTRY_INDEX_FILE_NAME))
+        .and_then(|mut file| {
+            file.write_all(serde_json::to_string_pretty(packages).unwrap().as_bytes())
+        })
+        .map_err(|e| format!("Failed to write {}: {}", REGISTRY_INDEX_FILE_NAME, e))
+}
+
+fn commit(git: &git::GitClient, files: Vec<&str>, message: String) -> Result<(), String> {
+    for file in files {
+        git.add(file.to_string())
+            .map_err(|e| format!("Failed to add {} to the registry: {}", file, e))?;
+    }
+    git.commit(message).map_err(|e| {
+        if e.stderr.contains("Author identity unknown") {
+            "git does not know who you are, set user.name and user.email with 'git config' first."
+                .to_string()
+        } else {
+            format!("Failed to commit to the registry: {}", e)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty git repository with its own identity, so the tests
+    /// don't depend on the git configuration of the host
+    fn temp_registry(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("grace-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.clone());
+        std::fs::create_dir_all(dir.clone()).unwrap();
+        for args in [
+            vec!["init", "-q"],
+            vec!["config", "user.name", "grace"],
+            vec!["config", "user.email", "grace@localhost"],
+        ] {
+            let status = std::process::Command::new("git")
+                .current_dir(dir.clone())
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        dir
+    }
+
+    fn read_index(path: &Path) -> PackageList {
+        let file = File::open(path.join(REGISTRY_INDEX_FILE_NAME)).unwrap();
+        serde_json::from_reader(BufReader::new(file)).unwrap()
+    }
+
+    #[test]
+    fn init_creates_empty_index() {
+        let dir = temp_registry("registry-init");
+        init(dir.clone()).unwrap();
+
+        assert!(read_index(&dir).packagelist.is_empty());
+        assert!(dir.join(REGISTRY_README_FILE_NAME).exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn init_refuses_existing_registry() {
+        let dir = temp_registry("registry-init-twice");
+        init(dir.clone()).unwrap();
+        assert!(init(dir.clone())
+            .unwrap_err()
+            .contains("already is a grace registry"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn add_package_adds_package_without_versions() {
+        let dir = temp_registry("registry-add");
+        init(dir.clone()).unwrap();
+        add_package(
+            dir.clone(),
+            "APackage".to_string(),
+            "https://example.com/a".to_string(),
+        )
+        .unwrap();
+
+        let index = read_index(&dir);
+        assert_eq!(index.packagelist.len(), 1);
+        assert_eq!(index.packagelist[0].name, "APackage");
+        assert_eq!(index.packagelist[0].uri, "https://example.com/a");
+        assert!(index.packagelist[0].versions.is_empty());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn add_package_refuses_duplicate() {
+        let dir = temp_registry("registry-add-twice");
+        init(dir.clone()).unwrap();
+        add_package(
+            dir.clone(),
+            "APackage".to_string(),
+            "https://example.com/a".to_string(),
+        )
+        .unwrap();
+        assert!(add_package(
+            dir.clone(),
+            "APackage".to_string(),
+            "https://example.com/b".to_string(),
+        )
+        .unwrap_err()
+        .contains("already contains a package APackage"));
+        assert_eq!(read_index(&dir).packagelist.len(), 1);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::package::{PackageDependency, PackageRequest};
+use super::project::GRACE_PACKAGE_FILE_NAME;
+
+/// Provides the resolver with the packages it can choose from
+pub trait PackageSource {
+    /// All known versions of a package
+    fn versions(&mut self, name: &str) -> Vec<PackageDependency>;
+
+    /// The requests found in the grace-packages.txt of a package version
+    fn dependencies(
+        &mut self,
+        package: &PackageDependency,
+    ) -> Result<Vec<PackageRequest>, ResolveError>;
+}
+
+#[derive(Debug, Clone)]
+pub enum ResolveError {
+    /// No combination of versions satisfies all requests. Contains an explanation
+    /// of the requests that could not be satisfied.
+    Conflict(String),
+    /// A package (transitively) depends on itself
+    Cycle(Vec<String>),
+    /// The source failed to provide the data of a package
+    Unavailable(String),
+    /// The grace-packages.txt of a package cannot be read
+    InvalidPackage(String),
+}
+
+/// A request together with the package that imposed it. required_by is None
+/// for requests of the root project.
+#[derive(Clone)]
+struct Constraint {
+    required_by: Option<PackageDependency>,
+    request: PackageRequest,
+}
+
+#[derive(Clone, Default)]
+struct State {
+    selected: Vec<PackageDependency>,
+    constraints: Vec<Constraint>,
+    pending: VecDeque<Constraint>,
+}
+
+/// Selects one version for every package in the dependency graph, such that
+/// all requests on a package are satisfied. If a choice leads to a conflict
+/// the resolver backtracks and tries the next older version.
+pub struct Resolver<'a, S: PackageSource> {
+    source: &'a mut S,
+    preferred: Vec<PackageDependency>,
+    versions: HashMap<String, Vec<PackageDependency>>,
+    dependencies: HashMap<String, Result<Vec<PackageRequest>, ResolveError>>,
+    /// The reason for the deepest dead end along with its depth
+    conflict: Option<(usize, ResolveError)>,
+}
+
+impl<'a, S: PackageSource> Resolver<'a, S> {
+    /// preferred versions (i.e. the locked ones) are tried before all others
+    pub fn new(source: &'a mut S, preferred: Vec<PackageDependency>) -> Self {
+        Self {
+            source,
+            preferred,
+            versions: HashMap::new(),
+            dependencies: HashMap::new(),
+            conflict: None,
+        }
+    }
+
+    pub fn resolve(
+        mut self,
+        requests: Vec<PackageRequest>,
+    ) -> Result<Vec<PackageDependency>, ResolveError> {
+        let mut state = State::default();
+        for request in requests {
+            state.pending.push_back(Constraint {
+                required_by: None,
+                request,
+            });
+        }
+
+        match self.solve(state) {
+            Some(solution) => {
+                if let Some(cycle) = find_cycle(&solution) {
+                    return Err(ResolveError::Cycle(cycle));
+                }
+                Ok(solution.selected)
+            }
+            None => Err(self
+                .conflict
+                .map(|x| x.1)
+                .unwrap_or_else(|| ResolveError::Conflict("No solution found.".to_string()))),
+        }
+    }
+
+    fn solve(&mut self, mut state: State) -> Option<State> {
+        let constraint = match state.pending.pop_front() {
+            Some(c) => c,
+            None => return Some(state),
+        };
+        let name = constraint.request.name.clone();
+        state.constraints.push(constraint.clone());
+
+        if let Some(selected) = state.selected.iter().find(|x| x.name == name) {
+            if selected.satisfies(&constraint.request) {
+                return self.solve(state);
+            }
+            self.record_conflict(&state, &name);
+            return None;
+        }
+
+        let candidates = self.candidates(&state, &name);
+        if candidates.is_empty() {
+            self.record_conflict(&state, &name);
+            return None;
+        }
+
+        for candidate in candidates {
+            // A version whose grace-packages.txt cannot be read is a dead end,
+            // other versions may still work
+            let requests = match self.dependencies_of(&candidate) {
+                Ok(requests) => requests,
+                Err(e) => {
+                    self.record_failure(&state, e);
+                    continue;
+                }
+            };
+            let mut next = state.clone();
+            for request in requests {
+                next.pending.push_back(Constraint {
+                    required_by: Some(candidate.clone()),
+                    request,
+                });
+            }
+            next.selected.push(candidate);
+
+            if let Some(solution) = self.solve(next) {
+                return Some(solution);
+            }
+        }
+        None
+    }
+
+    /// All versions of a package that satisfy every constraint on it, best first
+    fn candidates(&mut self, state: &State, name: &str) -> Vec<PackageDependency> {
+        if !self.versions.contains_key(name) {
+            let versions = self.source.versions(name);
+            self.versions.insert(name.to_string(), versions);
+        }
+
+        let mut candidates: Vec<PackageDependency> = self.versions[name]
+            .iter()
+            .filter(|v| {
+                state
+                    .constraints
+                    .iter()
+                    .filter(|c| c.request.name == name)
+                    .all(|c| v.satisfies(&c.request))
+            })
+            .cloned()
+            .collect();
+
+        candidates.sort_by_key(|x| std::cmp::Reverse(x.as_semver().ok()));
+
+        if let Some(pos) = candidates.iter().position(|c| {
+            self.preferred
+                .iter()
+                .any(|p| p.name == c.name && p.commit_hash == c.commit_hash)
+        }) {
+            let preferred = candidates.remove(pos);
+            candidates.insert(0, preferred);
+        }
+        candidates
+    }
+
+    fn dependencies_of(
+        &mut self,
+        package: &PackageDependency,
+    ) -> Result<Vec<PackageRequest>, ResolveError> {
+        let key = format!("{}@{}", package.name, package.commit_hash);
+        if !self.dependencies.contains_key(&key) {
+            let requests = self.source.dependencies(package);
+            self.dependencies.insert(key.clone(), requests);
+        }
+        self.dependencies[&key].clone()
+    }
+
+    /// Remembers a version that could not be used because its data is not
+    /// available, following the same rules as record_conflict
+    fn record_failure(&mut self, state: &State, error: ResolveError) {
+        let depth = state.selected.len();
+        if self.conflict.as_ref().is_some_and(|x| x.0 > depth) {
+            return;
+        }
+        self.conflict = Some((depth, error));
+    }
+
+    /// Remembers why the deepest dead end was reached, this is reported if no
+    /// solution can be found at all. Shallower dead ends are usually caused by
+    /// an early choice that backtracking got past later on. Of equally deep
+    /// dead ends the last one is kept.
+    fn record_conflict(&mut self, state: &State, name: &str) {
+        let depth = state.selected.len();
+        if self.conflict.as_ref().is_some_and(|x| x.0 > depth) {
+            return;
+        }
+
+        let mut explanation = format!("No version of {} satisfies all requirements:", name);
+        for c in state.constraints.iter().filter(|c| c.request.name == name) {
+            explanation.push_str(&format!(
+                "\n  {} requires {}",
+                origin_chain(state, c),
+                c.request
+            ));
+        }
+
+        let available: Vec<String> = self
+            .versions
+            .get(name)
+            .map(|v| v.iter().map(|x| x.version.clone()).collect())
+            .unwrap_or_default();
+        if available.is_empty() {
+            explanation.push_str("\n  No versions are available in your registries");
+        } else {
+            explanation.push_str(&format!("\n  Available versions: {}", available.join(", ")));
+        }
+
+        self.conflict = Some((depth, ResolveError::Conflict(explanation)));
+    }
+}
+
+/// Describes how a constraint came to be, e.g.
+/// "grace-packages.txt -> A 1.0.0 -> B 1.2.0"
+fn origin_chain(state: &State, constraint: &Constraint) -> String {
+    let mut chain = vec![];
+    let mut current = constraint.required_by.as_ref();
+    while let Some(package) = current {
+        // a cycle is reported on its own, just stop here
+        if chain.len() > state.selected.len() {
+            break;
+        }
+        chain.push(format!("{} {}", package.name, package.version));
+        current = state
+            .constraints
+            .iter()
+            .find(|c| c.request.name == package.name)
+            .and_then(|c| c.required_by.as_ref());
+    }
+    chain.push(GRACE_PACKAGE_FILE_NAME.to_string());
+    chain.reverse();
+    chain.join(" -> ")
+}
+
+/// Looks for a cycle in the dependencies between the selected packages
+fn find_cycle(state: &State) -> Option<Vec<String>> {
+    fn visit(
+        name: &str,
+        edges: &[(String, String)],
+        chain: &mut Vec<String>,
+        done: &mut Vec<String>,
+    ) -> Option<Vec<String>> {
+        if let Some(pos) = chain.iter().position(|x| x == name) {
+            let mut cycle = chain[pos..].to_vec();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+        if done.iter().any(|x| x == name) {
+            return None;
+        }
+
+        chain.push(name.to_string());
+        for (_, to) in edges.iter().filter(|(from, _)| from == name) {
+            if let Some(cycle) = visit(to, edges, chain, done) {
+                return Some(cycle);
+            }
+        }
+        chain.pop();
+        done.push(name.to_string());
+        None
+    }
+
+    let edges: Vec<(String, String)> = state
+        .constraints
+        .iter()
+        .filter_map(|c| {
+            c.required_by
+                .as_ref()
+                .map(|p| (p.name.clone(), c.request.name.clone()))
+        })
+        .collect();
+
+    let mut done = vec![];
+    for p in state.selected.iter() {
+        if let Some(cycle) = visit(&p.name, &edges, &mut vec![], &mut done) {
+            return Some(cycle);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{PackageSource, ResolveError, Resolver};
+    use crate::grace::package::{CheckoutOptions, PackageDependency, PackageRequest};
+
+    #[derive(Default)]
+    struct TestSource {
+        packages: HashMap<String, Vec<(PackageDependency, Vec<PackageRequest>)>>,
+        /// Versions whose dependencies cannot be read
+        broken: Vec<String>,
+    }
+
+    impl TestSource {
+        fn add(mut self, name: &str, version: &str, requests: &[&str]) -> Self {
+            let dep = PackageDependency {
+                name: name.to_string(),
+                version: version.to_string(),
+                uri: format!("https://example.com/{}", name),
+                commit_hash: format!("{}-{}", name, version),
+                checkout: CheckoutOptions::default(),
+            };
+            let requests = requests
+                .iter()
+                .map(|r| PackageRequest::from_string(r.to_string()))
+                .collect();
+            self.packages
+                .entry(name.to_string())
+                .or_default()
+                .push((dep, requests));
+            self
+        }
+
+        fn broken(mut self, name: &str, version: &str) -> Self {
+            self.broken.push(format!("{}-{}", name, version));
+            self.add(name, version, &[])
+        }
+    }
+
+    impl PackageSource for TestSource {
+        fn versions(&mut self, name: &str) -> Vec<PackageDependency> {
+            self.packages
+                .get(name)
+                .map(|v| v.iter().map(|x| x.0.clone()).collect())
+                .unwrap_or_default()
+        }
+
+        fn dependencies(
+            &mut self,
+            package: &PackageDependency,
+        ) -> Result<Vec<PackageRequest>, ResolveError> {
+            if self.broken.contains(&package.commit_hash) {
+                return Err(ResolveError::InvalidPackage(package.commit_hash.clone()));
+            }
+            Ok(self.packages[&package.name]
+                .iter()
+                .find(|x| x.0.version == package.version)
+                .map(|x| x.1.clone())
+                .unwrap_or_default())
+        }
+    }
+
+    fn resolve(
+        source: &mut TestSource,
+        requests: &[&str],
+        preferred: Vec<PackageDependency>,
+    ) -> Result<Vec<(String, String)>, ResolveError> {
+        let requests = requests
+            .iter()
+            .map(|r| PackageRequest::from_string(r.to_string()))
+            .collect();
+        Resolver::new(source, preferred)
+            .resolve(requests)
+            .map(|x| x.into_iter().map(|d| (d.name, d.version)).collect())
+    }
+
+    fn selected(name: &str, version: &str) -> (String, String) {
+        (name.to_string(), version.to_string())
+    }
+
+    #[test]
+    pub fn selects_newest_matching_version() {
+        let mut source = TestSource::default()
+            .add("A", "1.0.0", &[])
+            .add("A", "1.2.0", &[])
+            .add("A", "1.1.0", &[]);
+
+        let result = resolve(&mut source, &["A ~= 1.1.0"], vec![]).unwrap();
+        assert!(result == vec![selected("A", "1.1.0")]);
+    }
+
+    #[test]
+    pub fn ignores_prereleases_unless_requested() {
+        let mut source = TestSource::default()
+            .add("A", "1.0.0", &[])
+            .add("A", "1.1.0-rc.1", &[]);
+
+        let result = resolve(&mut source, &["A >= 1.0.0"], vec![]).unwrap();
+        assert!(result == vec![selected("A", "1.0.0")]);
+
+        let result = resolve(&mut source, &["A = 1.1.0-rc.1"], vec![]).unwrap();
+        assert!(result == vec![selected("A", "1.1.0-rc.1")]);
+    }
+
+    #[test]
+    pub fn resolves_transitive_dependencies() {
+        let mut source =
+            TestSource::default()
+                .add("A", "1.0.0", &["B = 1.0.0"])
+                .add("B", "1.0.0", &[]);
+
+        let result = resolve(&mut source, &["A = 1.0.0"], vec![]).unwrap();
+        assert!(result == vec![selected("A", "1.0.0"), selected("B", "1.0.0")]);
+    }
+
+    #[test]
+    pub fn backtracks_on_conflict() {
+        let mut source = TestSource::default()
+            .add("A", "1.0.0", &["C = 1.0.0"])
+            .add("A", "1.1.0", &["C = 2.0.0"])
+            .add("B", "1.0.0", &["C = 1.0.0"])
+            .add("C", "1.0.0", &[])
+            .add("C", "2.0.0", &[]);
+
+        let result = resolve(&mut source, &["A >= 1.0.0", "B = 1.0.0"], vec![]).unwrap();
+        assert!(result.contains(&selected("A", "1.0.0")));
+        assert!(result.contains(&selected("C", "1.0.0")));
+    }
+
+    #[test]
+    pub fn prefers_locked_version() {
+        let mut source = TestSource::default()
+            .add("A", "1.0.0", &[])
+            .add("A", "1.1.0", &[]);
+        let locked = source.versions("A")[0].clone();
+
+        let result = resolve(&mut source, &["A >= 1.0.0"], vec![locked]).unwrap();
+        assert!(result == vec![selected("A", "1.0.0")]);
+    }
+
+    #[test]
+    pub fn conflict_explains_constraints() {
+        let mut source = TestSource::default()
+            .add("A", "1.0.0", &[])
+            .add("A", "1.1.0", &[])
+            .add("B", "1.0.0", &["A = 1.1.0"]);
+
+        match resolve(&mut source, &["A = 1.0.0", "B = 1.0.0"], vec![]) {
+            Err(ResolveError::Conflict(explanation)) => {
+                assert!(explanation.contains("grace-packages.txt requires A =1.0.0"));
+                assert!(explanation.contains("B 1.0.0 requires A =1.1.0"));
+            }
+            _ => panic!("Expected a conflict"),
+        }
+    }
+
+    #[test]
+    pub fn conflict_reports_deepest_dead_end() {
+        // A 2.0.0 conflicts with B on C first, but even with A 1.0.0 there is
+        // no D that satisfies B
+        let mut source = TestSource::default()
+            .add("A", "1.0.0", &["C = 1.0.0"])
+            .add("A", "2.0.0", &["C = 2.0.0"])
+            .add("B", "1.0.0", &["C = 1.0.0", "D = 2.0.0"])
+            .add("C", "1.0.0", &[])
+            .add("C", "2.0.0", &[])
+            .add("D", "1.0.0", &[])
+            .add("E", "1.0.0", &["B = 1.0.0"]);
+
+        match resolve(&mut source, &["A >= 1.0.0", "E = 1.0.0"], vec![]) {
+            Err(ResolveError::Conflict(explanation)) => {
+                assert!(explanation.starts_with("No version of D"));
+                assert!(explanation
+                    .contains("grace-packages.txt -> E 1.0.0 -> B 1.0.0 requires D =2.0.0"));
+                assert!(explanation.contains("Available versions: 1.0.0"));
+            }
+            _ => panic!("Expected a conflict"),
+        }
+    }
+
+    #[test]
+    pub fn skips_versions_with_invalid_dependencies() {
+        let mut source = TestSource::default()
+            .add("A", "1.0.0", &[])
+            .broken("A", "1.1.0");
+
+        let result = resolve(&mut source, &["A >= 1.0.0"], vec![]).unwrap();
+        assert!(result == vec![selected("A", "1.0.0")]);
+
+        // without a usable version the reason is reported
+        match resolve(&mut source, &["A >= 1.1.0"], vec![]) {
+            Err(ResolveError::InvalidPackage(package)) => assert_eq!(package, "A-1.1.0"),
+            _ => panic!("Expected an invalid package"),
+        }
+    }
+
+    #[test]
+    pub fn detects_cycles() {
+        let mut source = TestSource::default().add("A", "1.0.0", &["B = 1.0.0"]).add(
+            "B",
+            "1.0.0",
+            &["A = 1.0.0"],
+        );
+
+        match resolve(&mut source, &["A = 1.0.0"], vec![]) {
+            Err(ResolveError::Cycle(cycle)) => assert!(cycle == vec!["A", "B", "A"]),
+            _ => panic!("Expected a cycle"),
+        }
+    }
+}
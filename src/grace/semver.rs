@@ -1,8 +1,53 @@
-#[derive(PartialEq, Clone)]
+use std::{cmp::Ordering, num::IntErrorKind, str::FromStr};
+
+/// A version as specified by SemVer 2.0 (https://semver.org), i.e.
+/// MAJOR.MINOR.PATCH with optional pre-release and build metadata:
+/// 1.0.0-rc.1+build.5
+#[derive(Clone, Debug)]
 pub struct SemanticVersion {
-    major: u16,
-    minor: u16,
-    patch: u16,
+    major: u64,
+    minor: u64,
+    patch: u64,
+    pre: Vec<Identifier>,
+    build: Vec<String>,
+}
+
+/// A single dot separated part of a pre-release
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Identifier {
+    Numeric(u64),
+    AlphaNumeric(String),
+}
+
+/// Reasons for a version string to be rejected
+#[derive(PartialEq, Debug)]
+pub enum VersionParseError {
+    /// One of major, minor or patch is missing
+    MissingComponent {
+        version: String,
+        component: &'static str,
+    },
+    /// More than three dot separated numbers were given
+    ExtraComponent { version: String },
+    /// major, minor or patch is not an unsigned integer
+    NonNumeric {
+        version: String,
+        component: &'static str,
+    },
+    /// major, minor or patch does not fit into 64 bits
+    Overflow {
+        version: String,
+        component: &'static str,
+    },
+    /// A pre-release identifier is empty, contains invalid characters or is
+    /// numeric with leading zeros
+    BadPreRelease { version: String, identifier: String },
+    /// A build metadata identifier is empty or contains invalid characters
+    BadBuildMetadata { version: String, identifier: String },
+    /// A requirement starts with an unknown operator
+    BadOperator { requirement: String },
+    /// A wildcard was used together with an operator or followed by a number
+    BadWildcard { requirement: String },
 }
 
 #[derive(PartialEq)]
@@ -10,86 +55,614 @@ pub enum Compatibility {
     /// Different Major
     Breaking, // 2.1.0 != 1.2.0
 
-    /// Same Major,Minor,Patch
+    /// Same Major,Minor,Patch and pre-release
     Exact, // 1.0.1 == 1.0.1
-    /// Same Major+Minor, diffent patch
-    Partial, // 1.1.0 ~= 1.1.4
+    /// Same Major+Minor, diffent patch or pre-release
+    Partial, // 1.1.0 partial 1.1.4
 
     /// Same Major, different minor or patch
     Compatible, // 1.1.3 compat 1.2.0
 }
 
 impl SemanticVersion {
-    pub fn new(major: u16, minor: u16, patch: u16) -> Self {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
         Self {
             major,
             minor,
             patch,
+            pre: vec![],
+            build: vec![],
         }
     }
-    pub fn from_string(data: String) -> SemanticVersion {
-        let parts: Vec<&str> = data.split(".").collect();
-        let major = parts[0]
-            .parse::<u16>()
-            .expect(format!("Need unsigned integer as major version: {}", data).as_str());
-        let minor = parts[1]
-            .parse::<u16>()
-            .expect(format!("Need unsigned integer as minor version: {}", data).as_str());
-        let patch = parts[2]
-            .parse::<u16>()
-            .expect(format!("Need unsigned integer as patch version: {}", data).as_str());
-        return SemanticVersion::new(major, minor, patch);
+
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
     }
 
     pub fn match_to(&self, other: &SemanticVersion) -> Compatibility {
         if self.major == other.major {
             if self.minor == other.minor {
-                if self.patch == other.patch {
+                if self.patch == other.patch && self.pre == other.pre {
                     return Compatibility::Exact;
                 }
                 return Compatibility::Partial;
             }
             return Compatibility::Compatible;
         }
-        return Compatibility::Breaking;
+        Compatibility::Breaking
+    }
+}
+
+impl FromStr for SemanticVersion {
+    type Err = VersionParseError;
+
+    /// Parses a version such as 1.2.3, 1.0.0-rc.1 or 1.0.0+build.5. A leading
+    /// "v" (as often found in git tags) is ignored.
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let version = data.strip_prefix('v').unwrap_or(data);
+        let (version, build) = match version.split_once('+') {
+            Some((v, b)) => (v, Some(b)),
+            None => (version, None),
+        };
+        let (core, pre) = match version.split_once('-') {
+            Some((c, p)) => (c, Some(p)),
+            None => (version, None),
+        };
+
+        let mut parts = core.split('.');
+        let major = parse_component(data, parts.next(), "major")?;
+        let minor = parse_component(data, parts.next(), "minor")?;
+        let patch = parse_component(data, parts.next(), "patch")?;
+        if parts.next().is_some() {
+            return Err(VersionParseError::ExtraComponent {
+                version: data.to_string(),
+            });
+        }
+
+        let mut result = SemanticVersion::new(major, minor, patch);
+        if let Some(pre) = pre {
+            for x in pre.split('.') {
+                let bad = || VersionParseError::BadPreRelease {
+                    version: data.to_string(),
+                    identifier: x.to_string(),
+                };
+                if !is_identifier(x) {
+                    return Err(bad());
+                }
+                result.pre.push(match x.parse::<u64>() {
+                    Ok(n) if x == "0" || !x.starts_with('0') => Identifier::Numeric(n),
+                    Ok(_) => return Err(bad()),
+                    // Numeric identifiers that do not fit into u64 are still
+                    // numeric and must not be compared as strings
+                    Err(_) if x.chars().all(|c| c.is_ascii_digit()) => return Err(bad()),
+                    Err(_) => Identifier::AlphaNumeric(x.to_string()),
+                });
+            }
+        }
+        if let Some(build) = build {
+            for x in build.split('.') {
+                if !is_identifier(x) {
+                    return Err(VersionParseError::BadBuildMetadata {
+                        version: data.to_string(),
+                        identifier: x.to_string(),
+                    });
+                }
+                result.build.push(x.to_string());
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn parse_component(
+    version: &str,
+    part: Option<&str>,
+    component: &'static str,
+) -> Result<u64, VersionParseError> {
+    let part = match part {
+        Some(p) if !p.is_empty() => p,
+        _ => {
+            return Err(VersionParseError::MissingComponent {
+                version: version.to_string(),
+                component,
+            })
+        }
+    };
+
+    part.parse::<u64>().map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow => VersionParseError::Overflow {
+            version: version.to_string(),
+            component,
+        },
+        _ => VersionParseError::NonNumeric {
+            version: version.to_string(),
+            component,
+        },
+    })
+}
+
+impl std::fmt::Display for VersionParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionParseError::MissingComponent { version, component } => {
+                write!(f, "Missing {} version in \"{}\"", component, version)
+            }
+            VersionParseError::ExtraComponent { version } => write!(
+                f,
+                "Expected a version of form MAJOR.MINOR.PATCH, got \"{}\"",
+                version
+            ),
+            VersionParseError::NonNumeric { version, component } => write!(
+                f,
+                "Need unsigned integer as {} version in \"{}\"",
+                component, version
+            ),
+            VersionParseError::Overflow { version, component } => {
+                write!(
+                    f,
+                    "The {} version of \"{}\" is too large",
+                    component, version
+                )
+            }
+            VersionParseError::BadPreRelease {
+                version,
+                identifier,
+            } => write!(
+                f,
+                "Invalid pre-release identifier \"{}\" in \"{}\"",
+                identifier, version
+            ),
+            VersionParseError::BadBuildMetadata {
+                version,
+                identifier,
+            } => write!(
+                f,
+                "Invalid build metadata \"{}\" in \"{}\"",
+                identifier, version
+            ),
+            VersionParseError::BadOperator { requirement } => {
+                write!(f, "Unknown operator in requirement \"{}\"", requirement)
+            }
+            VersionParseError::BadWildcard { requirement } => {
+                write!(f, "Invalid wildcard in requirement \"{}\"", requirement)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VersionParseError {}
+
+/// Identifiers are non-empty and consist of ASCII alphanumerics and hyphens
+fn is_identifier(data: &str) -> bool {
+    !data.is_empty() && data.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+impl std::fmt::Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identifier::Numeric(n) => write!(f, "{}", n),
+            Identifier::AlphaNumeric(s) => f.write_str(s),
+        }
     }
 }
 
 impl std::fmt::Display for SemanticVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(format!("{}.{}.{}", self.major, self.minor, self.patch).as_str())
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            let pre: Vec<String> = self.pre.iter().map(|x| x.to_string()).collect();
+            write!(f, "-{}", pre.join("."))?;
+        }
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for Identifier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Identifier::Numeric(a), Identifier::Numeric(b)) => a.cmp(b),
+            // numeric identifiers always have lower precedence
+            (Identifier::Numeric(_), Identifier::AlphaNumeric(_)) => Ordering::Less,
+            (Identifier::AlphaNumeric(_), Identifier::Numeric(_)) => Ordering::Greater,
+            (Identifier::AlphaNumeric(a), Identifier::AlphaNumeric(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for Identifier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Build metadata does not take part in precedence, so 1.0.0+a == 1.0.0+b
+impl PartialEq for SemanticVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SemanticVersion {}
+
+impl Ord for SemanticVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let release = self
+            .major
+            .cmp(&other.major)
+            .then(self.minor.cmp(&other.minor))
+            .then(self.patch.cmp(&other.patch));
+        if release != Ordering::Equal {
+            return release;
+        }
+
+        cmp_pre(&self.pre, &other.pre)
+    }
+}
+
+/// Compares two pre-releases, an empty pre-release (i.e. the release itself)
+/// has a higher precedence than any pre-release
+fn cmp_pre(a: &[Identifier], b: &[Identifier]) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.cmp(b),
     }
 }
 
 impl PartialOrd for SemanticVersion {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        if self.major > other.major {
-            return Some(std::cmp::Ordering::Greater);
-        } else if self.major < other.major {
-            return Some(std::cmp::Ordering::Less);
-        } else {
-            // Same Major version
-            if self.minor > other.minor {
-                return Some(std::cmp::Ordering::Greater);
-            } else if self.minor < other.minor {
-                return Some(std::cmp::Ordering::Less);
-            } else {
-                // same Major & same Minor
-                if self.patch > other.patch {
-                    return Some(std::cmp::Ordering::Greater);
-                } else if self.patch < other.patch {
-                    return Some(std::cmp::Ordering::Less);
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Operators of a single comparator in a version requirement
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Op {
+    /// =1.2.3, partial versions match all versions starting with them
+    Exact,
+    /// >1.2.3
+    Greater,
+    /// >=1.2.3
+    GreaterEq,
+    /// <1.2.3
+    Less,
+    /// <=1.2.3
+    LessEq,
+    /// !=1.2.3, the opposite of Exact
+    NotEq,
+    /// ~1.2.3 or ~=1.2.3: only patch updates (>=1.2.3, <1.3.0)
+    Tilde,
+    /// ^1.2.3 or 1.2.3: updates that do not change the leftmost non-zero
+    /// component (>=1.2.3, <2.0.0)
+    Caret,
+    /// 1.* or 1.2.*
+    Wildcard,
+}
+
+/// A single comparison such as >=1.2 or ~1.2.3. Components left out are None.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Comparator {
+    pub op: Op,
+    pub major: u64,
+    pub minor: Option<u64>,
+    pub patch: Option<u64>,
+    pub pre: Vec<Identifier>,
+}
+
+/// A version requirement as used by Cargo, i.e. a comma separated list of
+/// comparators that all have to match: ">=1.2, <1.5". An empty list (written
+/// as "*") matches every version.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct VersionReq {
+    pub comparators: Vec<Comparator>,
+}
+
+impl VersionReq {
+    pub fn matches(&self, version: &SemanticVersion) -> bool {
+        if !self.comparators.iter().all(|c| c.matches(version)) {
+            return false;
+        }
+
+        // Pre-releases are only used if a comparator explicitly asks for a
+        // pre-release of the very same major.minor.patch
+        !version.is_prerelease() || self.comparators.iter().any(|c| c.allows_pre(version))
+    }
+}
+
+impl Comparator {
+    pub fn matches(&self, version: &SemanticVersion) -> bool {
+        match self.op {
+            Op::Exact | Op::Wildcard => self.matches_exact(version),
+            Op::Greater => self.matches_greater(version),
+            Op::GreaterEq => self.matches_exact(version) || self.matches_greater(version),
+            Op::Less => self.matches_less(version),
+            Op::LessEq => self.matches_exact(version) || self.matches_less(version),
+            Op::NotEq => !self.matches_exact(version),
+            Op::Tilde => self.matches_tilde(version),
+            Op::Caret => self.matches_caret(version),
+        }
+    }
+
+    fn allows_pre(&self, version: &SemanticVersion) -> bool {
+        !self.pre.is_empty()
+            && self.major == version.major
+            && self.minor == Some(version.minor)
+            && self.patch == Some(version.patch)
+    }
+
+    fn matches_exact(&self, version: &SemanticVersion) -> bool {
+        if version.major != self.major {
+            return false;
+        }
+        if let Some(minor) = self.minor {
+            if version.minor != minor {
+                return false;
+            }
+        }
+        if let Some(patch) = self.patch {
+            if version.patch != patch {
+                return false;
+            }
+        }
+        version.pre == self.pre
+    }
+
+    fn matches_greater(&self, version: &SemanticVersion) -> bool {
+        if version.major != self.major {
+            return version.major > self.major;
+        }
+        match self.minor {
+            None => return false,
+            Some(minor) if version.minor != minor => return version.minor > minor,
+            _ => {}
+        }
+        match self.patch {
+            None => return false,
+            Some(patch) if version.patch != patch => return version.patch > patch,
+            _ => {}
+        }
+        cmp_pre(&version.pre, &self.pre) == Ordering::Greater
+    }
+
+    fn matches_less(&self, version: &SemanticVersion) -> bool {
+        if version.major != self.major {
+            return version.major < self.major;
+        }
+        match self.minor {
+            None => return false,
+            Some(minor) if version.minor != minor => return version.minor < minor,
+            _ => {}
+        }
+        match self.patch {
+            None => return false,
+            Some(patch) if version.patch != patch => return version.patch < patch,
+            _ => {}
+        }
+        cmp_pre(&version.pre, &self.pre) == Ordering::Less
+    }
+
+    fn matches_tilde(&self, version: &SemanticVersion) -> bool {
+        if version.major != self.major {
+            return false;
+        }
+        if let Some(minor) = self.minor {
+            if version.minor != minor {
+                return false;
+            }
+        }
+        if let Some(patch) = self.patch {
+            if version.patch != patch {
+                return version.patch > patch;
+            }
+        }
+        cmp_pre(&version.pre, &self.pre) != Ordering::Less
+    }
+
+    fn matches_caret(&self, version: &SemanticVersion) -> bool {
+        if version.major != self.major {
+            return false;
+        }
+        let minor = match self.minor {
+            Some(m) => m,
+            None => return true,
+        };
+        let patch = match self.patch {
+            Some(p) => p,
+            None => {
+                return if self.major > 0 {
+                    version.minor >= minor
                 } else {
-                    return Some(std::cmp::Ordering::Equal);
+                    version.minor == minor
                 }
             }
+        };
+
+        if self.major > 0 {
+            if version.minor != minor {
+                return version.minor > minor;
+            } else if version.patch != patch {
+                return version.patch > patch;
+            }
+        } else if minor > 0 {
+            if version.minor != minor {
+                return false;
+            } else if version.patch != patch {
+                return version.patch > patch;
+            }
+        } else if version.minor != minor || version.patch != patch {
+            return false;
+        }
+        cmp_pre(&version.pre, &self.pre) != Ordering::Less
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = VersionParseError;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let data = data.trim();
+        if data == "*" {
+            return Ok(VersionReq {
+                comparators: vec![],
+            });
         }
+
+        let comparators = data
+            .split(',')
+            .map(|x| x.parse::<Comparator>())
+            .collect::<Result<Vec<Comparator>, VersionParseError>>()?;
+        Ok(VersionReq { comparators })
+    }
+}
+
+impl FromStr for Comparator {
+    type Err = VersionParseError;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let data = data.trim();
+        let operators = [
+            (">=", Op::GreaterEq),
+            ("<=", Op::LessEq),
+            ("!=", Op::NotEq),
+            ("~=", Op::Tilde),
+            ("=", Op::Exact),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("~", Op::Tilde),
+            ("^", Op::Caret),
+        ];
+        let (op, version) = match operators.iter().find(|(o, _)| data.starts_with(o)) {
+            Some((o, op)) => (Some(*op), data[o.len()..].trim_start()),
+            None => (None, data),
+        };
+        if version.starts_with(|c: char| !c.is_ascii_alphanumeric() && c != '*') {
+            return Err(VersionParseError::BadOperator {
+                requirement: data.to_string(),
+            });
+        }
+
+        let (core, pre) = match version.split_once('-') {
+            Some((c, p)) => (c, Some(p)),
+            None => (version, None),
+        };
+        let parts: Vec<&str> = core.split('.').collect();
+        if parts.len() > 3 {
+            return Err(VersionParseError::ExtraComponent {
+                version: data.to_string(),
+            });
+        }
+
+        let is_wildcard = |x: &str| x == "*" || x == "x" || x == "X";
+        let wildcard = parts.iter().position(|x| is_wildcard(x));
+        if let Some(pos) = wildcard {
+            // Everything after a wildcard has to be a wildcard as well
+            if pos == 0
+                || op.is_some()
+                || pre.is_some()
+                || parts[pos..].iter().any(|x| !is_wildcard(x))
+            {
+                return Err(VersionParseError::BadWildcard {
+                    requirement: data.to_string(),
+                });
+            }
+        }
+        let numbers = &parts[..wildcard.unwrap_or(parts.len())];
+
+        let major = parse_component(data, numbers.first().copied(), "major")?;
+        let minor = match numbers.get(1) {
+            Some(m) => Some(parse_component(data, Some(m), "minor")?),
+            None => None,
+        };
+        let patch = match numbers.get(2) {
+            Some(p) => Some(parse_component(data, Some(p), "patch")?),
+            None => None,
+        };
+
+        let pre = match pre {
+            Some(p) => {
+                if patch.is_none() {
+                    return Err(VersionParseError::MissingComponent {
+                        version: data.to_string(),
+                        component: "patch",
+                    });
+                }
+                // Reuse the version parser for the identifiers
+                format!("0.0.0-{}", p)
+                    .parse::<SemanticVersion>()
+                    .map_err(|_| VersionParseError::BadPreRelease {
+                        version: data.to_string(),
+                        identifier: p.to_string(),
+                    })?
+                    .pre
+            }
+            None => vec![],
+        };
+
+        let op = match (op, wildcard) {
+            (Some(op), _) => op,
+            (None, Some(_)) => Op::Wildcard,
+            (None, None) => Op::Caret,
+        };
+
+        Ok(Comparator {
+            op,
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+impl std::fmt::Display for Comparator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self.op {
+            Op::Exact => "=",
+            Op::Greater => ">",
+            Op::GreaterEq => ">=",
+            Op::Less => "<",
+            Op::LessEq => "<=",
+            Op::NotEq => "!=",
+            Op::Tilde => "~",
+            Op::Caret => "^",
+            Op::Wildcard => "",
+        })?;
+        write!(f, "{}", self.major)?;
+        match self.minor {
+            Some(minor) => write!(f, ".{}", minor)?,
+            None if self.op == Op::Wildcard => return f.write_str(".*"),
+            None => return Ok(()),
+        }
+        match self.patch {
+            Some(patch) => write!(f, ".{}", patch)?,
+            None if self.op == Op::Wildcard => return f.write_str(".*"),
+            None => return Ok(()),
+        }
+        if !self.pre.is_empty() {
+            let pre: Vec<String> = self.pre.iter().map(|x| x.to_string()).collect();
+            write!(f, "-{}", pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.comparators.is_empty() {
+            return f.write_str("*");
+        }
+        let comparators: Vec<String> = self.comparators.iter().map(|x| x.to_string()).collect();
+        f.write_str(&comparators.join(", "))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Compatibility, SemanticVersion};
+    use super::{Compatibility, SemanticVersion, VersionParseError, VersionReq};
 
     #[test]
     pub fn same_version_equals() {
@@ -143,4 +716,272 @@ mod tests {
         let v2 = SemanticVersion::new(2, 2, 4);
         assert!(v1 < v2);
     }
+
+    #[test]
+    pub fn parses_prerelease_and_build() {
+        let v = "1.0.0-rc.1+build.5".parse::<SemanticVersion>().unwrap();
+        assert!(v.is_prerelease());
+        assert!(v.to_string() == "1.0.0-rc.1+build.5");
+    }
+
+    #[test]
+    pub fn ignores_leading_v() {
+        let v = "v1.2.3".parse::<SemanticVersion>().unwrap();
+        assert!(v == SemanticVersion::new(1, 2, 3));
+    }
+
+    #[test]
+    pub fn supports_large_components() {
+        let v = "20221201.0.70000".parse::<SemanticVersion>().unwrap();
+        assert!(v.to_string() == "20221201.0.70000");
+    }
+
+    #[test]
+    pub fn prerelease_precedence() {
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            let v1 = pair[0].parse::<SemanticVersion>().unwrap();
+            let v2 = pair[1].parse::<SemanticVersion>().unwrap();
+            assert!(v1 < v2, "{} < {}", v1, v2);
+        }
+    }
+
+    #[test]
+    pub fn build_metadata_is_ignored_for_precedence() {
+        let v1 = "1.0.0+build.1".parse::<SemanticVersion>().unwrap();
+        let v2 = "1.0.0+build.2".parse::<SemanticVersion>().unwrap();
+        assert!(v1 == v2);
+    }
+
+    #[test]
+    pub fn rejects_missing_component() {
+        let e = "1.2".parse::<SemanticVersion>().unwrap_err();
+        assert!(matches!(
+            e,
+            VersionParseError::MissingComponent {
+                component: "patch",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    pub fn rejects_extra_component() {
+        let e = "1.2.3.4".parse::<SemanticVersion>().unwrap_err();
+        assert!(matches!(e, VersionParseError::ExtraComponent { .. }));
+    }
+
+    #[test]
+    pub fn rejects_non_numeric() {
+        let e = "1.x.0".parse::<SemanticVersion>().unwrap_err();
+        assert!(matches!(
+            e,
+            VersionParseError::NonNumeric {
+                component: "minor",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    pub fn rejects_overflow() {
+        let e = "1.0.18446744073709551616"
+            .parse::<SemanticVersion>()
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            VersionParseError::Overflow {
+                component: "patch",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    pub fn rejects_bad_prerelease() {
+        for v in ["1.0.0-", "1.0.0-rc..1", "1.0.0-01", "1.0.0-r$c"] {
+            let e = v.parse::<SemanticVersion>().unwrap_err();
+            assert!(
+                matches!(e, VersionParseError::BadPreRelease { .. }),
+                "{}",
+                v
+            );
+        }
+    }
+
+    #[test]
+    pub fn rejects_bad_build_metadata() {
+        let e = "1.0.0+".parse::<SemanticVersion>().unwrap_err();
+        assert!(matches!(e, VersionParseError::BadBuildMetadata { .. }));
+    }
+
+    fn req_matches(req: &str, version: &str) -> bool {
+        req.parse::<VersionReq>()
+            .unwrap()
+            .matches(&version.parse::<SemanticVersion>().unwrap())
+    }
+
+    #[test]
+    pub fn caret_requirement() {
+        assert!(req_matches("^1.2", "1.9.0"));
+        assert!(!req_matches("^1.2", "2.0.0"));
+        assert!(req_matches("1.2.3", "1.4.0"));
+        assert!(!req_matches("^0.2.3", "0.3.0"));
+        assert!(!req_matches("^0.0.3", "0.0.4"));
+    }
+
+    #[test]
+    pub fn tilde_requirement() {
+        assert!(req_matches("~1.2.3", "1.2.9"));
+        assert!(!req_matches("~1.2.3", "1.3.0"));
+        assert!(!req_matches("~1.2.3", "1.2.2"));
+    }
+
+    #[test]
+    pub fn wildcard_requirement() {
+        assert!(req_matches("1.*", "1.7.3"));
+        assert!(!req_matches("1.*", "2.0.0"));
+        assert!(req_matches("*", "42.0.0"));
+    }
+
+    #[test]
+    pub fn compound_requirement() {
+        assert!(req_matches(">=1.2, <1.5", "1.4.9"));
+        assert!(!req_matches(">=1.2, <1.5", "1.5.0"));
+        assert!(!req_matches(">1.0, !=1.3.0", "1.3.0"));
+        assert!(req_matches(">1.0, !=1.3.0", "1.3.1"));
+    }
+
+    #[test]
+    pub fn requirement_roundtrip() {
+        for req in [
+            ">=1.2, <1.5",
+            "^1.2.3",
+            "~1.2",
+            "1.*",
+            "!=1.3.0",
+            "=1.0.0-rc.1",
+            "*",
+        ] {
+            assert!(req.parse::<VersionReq>().unwrap().to_string() == req);
+        }
+    }
+
+    #[test]
+    pub fn rejects_bad_requirements() {
+        for req in ["=>1.0.0", ">=1.*", "*.1", "1.*.1", "1.2.3.4", ""] {
+            assert!(req.parse::<VersionReq>().is_err(), "{}", req);
+        }
+    }
+
+    #[test]
+    pub fn requirement_matching_table() {
+        // (requirement, version, expected)
+        let table = [
+            // =
+            ("=1.2.3", "1.2.3", true),
+            ("=1.2.3", "1.2.4", false),
+            ("= 1.2.3", "1.2.3", true),
+            ("=1.2", "1.2.9", true),
+            ("=1.2", "1.3.0", false),
+            ("=1", "1.9.9", true),
+            ("=1", "2.0.0", false),
+            // >=
+            (">=1.5.0", "1.5.0", true),
+            (">=1.5.0", "1.2.0", false),
+            (">=1.5.0", "2.0.0", true),
+            (">= 1.5.0", "1.9.0", true),
+            (">=1.5", "1.5.0", true),
+            (">=1", "0.9.9", false),
+            // >
+            (">1.2.3", "1.2.3", false),
+            (">1.2.3", "1.2.4", true),
+            (">1.2", "1.2.9", false),
+            (">1.2", "1.3.0", true),
+            (">1", "1.9.0", false),
+            (">1", "2.0.0", true),
+            // <
+            ("<2.0.0", "1.9.9", true),
+            ("<2.0.0", "2.0.0", false),
+            ("<1.2", "1.1.9", true),
+            ("<1.2", "1.2.0", false),
+            // <=
+            ("<=1.2.3", "1.2.3", true),
+            ("<=1.2.3", "1.2.4", false),
+            ("<=1.2", "1.2.9", true),
+            ("<=1.2", "1.3.0", false),
+            // !=
+            ("!=1.3.0", "1.3.0", false),
+            ("!=1.3.0", "1.3.1", true),
+            ("!=1.3", "1.3.5", false),
+            // ~= and ~
+            ("~=1.1.4", "1.1.4", true),
+            ("~=1.1.4", "1.1.9", true),
+            ("~=1.1.4", "1.1.0", false),
+            ("~=1.1.4", "1.2.0", false),
+            ("~= 1.1", "1.1.0", true),
+            ("~=1", "1.9.0", true),
+            ("~1.2.3", "1.2.5", true),
+            ("~1", "2.0.0", false),
+            // ^
+            ("^1.2.3", "1.2.3", true),
+            ("^1.2.3", "1.9.0", true),
+            ("^1.2.3", "1.2.2", false),
+            ("^1.2.3", "2.0.0", false),
+            ("^0.2.3", "0.2.9", true),
+            ("^0.2.3", "0.3.0", false),
+            ("^0.0.3", "0.0.3", true),
+            ("^0.0.3", "0.0.4", false),
+            ("^0.0", "0.0.7", true),
+            ("^0.0", "0.1.0", false),
+            ("^0", "0.9.0", true),
+            ("^1", "1.9.0", true),
+            ("1.2.3", "1.3.0", true),
+            // wildcards
+            ("*", "0.0.1", true),
+            ("1.*", "1.0.0", true),
+            ("1.*", "2.0.0", false),
+            ("1.2.*", "1.2.7", true),
+            ("1.2.*", "1.3.0", false),
+            ("1.x", "1.4.0", true),
+            // compound
+            (">=1.2, <1.5", "1.2.0", true),
+            (">=1.2, <1.5", "1.5.0", false),
+            (">=1.2, <1.5", "1.1.9", false),
+            (">1.0, !=1.3.0", "1.3.0", false),
+            // pre-releases
+            (">=1.0.0", "1.1.0-rc.1", false),
+            ("*", "1.0.0-rc.1", false),
+            ("<1.0.0", "1.0.0-rc.1", false),
+            ("=1.0.0-rc.1", "1.0.0-rc.1", true),
+            ("=1.0.0-rc.1", "1.0.0", false),
+            (">=1.0.0-rc.1", "1.0.0-rc.2", true),
+            (">=1.0.0-rc.1", "1.0.0", true),
+            (">=1.0.0-rc.1", "1.0.1-rc.1", false),
+            ("^1.0.0-beta", "1.0.0-alpha", false),
+            ("^1.0.0-beta", "1.0.0-beta.2", true),
+            ("~1.2.3-beta", "1.2.3-beta", true),
+            // build metadata is ignored
+            ("=1.0.0", "1.0.0+build.5", true),
+        ];
+
+        for (req, version, expected) in table {
+            assert!(
+                req_matches(req, version) == expected,
+                "{} matching {} should be {}",
+                req,
+                version,
+                expected
+            );
+        }
+    }
 }
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
+use grace::{git::Credentials, package::InstallMode, Registry};
 
 mod grace;
 
@@ -8,6 +9,13 @@ fn cli() -> Command {
     Command::new("grace")
         .about("Your Git Nanny")
         .subcommand_required(true)
+        .arg(
+            Arg::new("git-backend")
+                .long("git-backend")
+                .global(true)
+                .value_parser(["cli", "libgit2"])
+                .help("The git implementation to use, defaults to $GRACE_GIT_BACKEND or cli"),
+        )
         .subcommand(Command::new("init").about("Initialize a new project"))
         .subcommand(
             Command::new("registry")
@@ -16,28 +24,149 @@ fn cli() -> Command {
                 .subcommand(
                     Command::new("add")
                         .arg_required_else_help(true)
-                        .arg(Arg::new("uri").help("The URI of the registry")),
+                        .arg(Arg::new("uri").help("The URI of the registry"))
+                        .arg(Arg::new("branch").long("branch").help(
+                            "The branch holding the index, defaults to the registry's default branch",
+                        ))
+                        .arg(
+                            Arg::new("ssh-key")
+                                .long("ssh-key")
+                                .help("Private key for the registry and its packages"),
+                        )
+                        .arg(
+                            Arg::new("askpass")
+                                .long("askpass")
+                                .help("Program that provides user name and password, like GIT_ASKPASS"),
+                        )
+                        .arg(
+                            Arg::new("token-env")
+                                .long("token-env")
+                                .help("Environment variable holding an access token"),
+                        )
+                        .arg(
+                            Arg::new("username")
+                                .long("username")
+                                .help("User name sent along with the token"),
+                        ),
                 )
                 .subcommand(Command::new("update"))
-                .subcommand(Command::new("remove")),
+                .subcommand(
+                    Command::new("init")
+                        .about("Creates a new registry repository")
+                        .arg_required_else_help(true)
+                        .arg(
+                            Arg::new("path")
+                                .required(true)
+                                .help("The folder to create the registry in"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("add-package")
+                        .about("Adds a package without versions to the registry in the current folder")
+                        .arg_required_else_help(true)
+                        .arg(Arg::new("name").required(true).help("The name of the package"))
+                        .arg(
+                            Arg::new("uri")
+                                .required(true)
+                                .help("The URI of the package's repository"),
+                        )
+                        .arg(
+                            Arg::new("path")
+                                .long("path")
+                                .default_value(".")
+                                .help("The folder of the registry"),
+                        ),
+                )
+                .subcommand(Command::new("list").about("Lists the registries of the project"))
+                .subcommand(
+                    Command::new("info")
+                        .about("Shows the packages offered by a registry")
+                        .arg_required_else_help(true)
+                        .arg(
+                            Arg::new("uri")
+                                .required(true)
+                                .help("The URI of the registry"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Removes a registry, installed packages are kept")
+                        .arg_required_else_help(true)
+                        .arg(
+                            Arg::new("uri")
+                                .required(true)
+                                .help("The URI of the registry"),
+                        ),
+                ),
         )
         .subcommand(
             Command::new("package")
                 .about("Interact with packages")
                 .subcommand_required(true)
-                .subcommand(Command::new("install").about("Installs all packages from grace.toml"))
                 .subcommand(
-                    Command::new("update").about(
-                        "Updates all packages from grace.toml to the latest compatible version",
-                    ),
+                    Command::new("install")
+                        .about("Installs all packages from grace.toml")
+                        .arg(
+                            Arg::new("locked")
+                                .long("locked")
+                                .action(ArgAction::SetTrue)
+                                .help("Fail if grace-lock.json would have to be changed"),
+                        )
+                        .arg(
+                            Arg::new("frozen")
+                                .long("frozen")
+                                .action(ArgAction::SetTrue)
+                                .help(
+                                    "Like --locked, but also fail if packages have to be fetched",
+                                ),
+                        ),
                 )
-                .subcommand(Command::new("publish")),
+                .subcommand(
+                    Command::new("update")
+                        .about(
+                            "Updates all packages from grace.toml to the latest compatible version",
+                        )
+                        .arg(
+                            Arg::new("packages")
+                                .num_args(0..)
+                                .help("Only update these packages"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("publish")
+                        .about("Publishes a new version of a package to its registry")
+                        .arg_required_else_help(true)
+                        .arg(
+                            Arg::new("package")
+                                .required(true)
+                                .help("The package and version to publish, e.g. APackage/1.0.1"),
+                        )
+                        .arg(
+                            Arg::new("uri").help(
+                                "The URI of the registry to publish to, if the package is new",
+                            ),
+                        ),
+                ),
         )
 }
 
 fn main() {
     let matches = cli().get_matches();
 
+    let backend = matches
+        .get_one::<String>("git-backend")
+        .cloned()
+        .or_else(|| std::env::var(grace::git::GIT_BACKEND_ENV).ok());
+    if let Some(backend) = backend {
+        match backend.parse() {
+            Ok(b) => grace::git::select_backend(b),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     match matches.subcommand() {
         Some(("init", _)) => {
             init_project();
@@ -54,8 +183,61 @@ fn main() {
 
 fn do_package_command(submatches: &clap::ArgMatches) {
     match submatches.subcommand() {
-        Some(("install", _)) => {
-            grace::package::PackageDependency::install(PathBuf::from("."))
+        Some(("install", submatches)) => {
+            let mode = if submatches.get_flag("frozen") {
+                InstallMode::Frozen
+            } else if submatches.get_flag("locked") {
+                InstallMode::Locked
+            } else {
+                InstallMode::Normal
+            };
+
+            if let Err(e) = grace::package::PackageDependency::install(PathBuf::from("."), mode) {
+                println!("{}", e);
+                std::process::exit(e.exit_code());
+            }
+        }
+
+        Some(("update", submatches)) => {
+            let packages = submatches
+                .get_many::<String>("packages")
+                .map(|x| x.cloned().collect())
+                .unwrap_or_default();
+            if let Err(e) = grace::package::PackageDependency::update(PathBuf::from("."), packages)
+            {
+                println!("{}", e);
+                std::process::exit(e.exit_code());
+            }
+        }
+
+        Some(("publish", submatches)) => {
+            let package = submatches.get_one::<String>("package").unwrap();
+            let (name, version) = match package.split_once('/') {
+                Some(x) => x,
+                None => {
+                    println!(
+                        "Expected a package of form <package>/<version>, e.g. APackage/1.0.1. Got: {}",
+                        package
+                    );
+                    std::process::exit(1);
+                }
+            };
+            let version = match version.parse() {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let project = grace::project::Project::open(PathBuf::from("."));
+            if let Err(e) = project.publish_package(
+                name.to_string(),
+                version,
+                submatches.get_one::<String>("uri").cloned(),
+            ) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
         }
 
         _ => unreachable!(),
@@ -71,7 +253,20 @@ fn do_registry_command(submatches: &clap::ArgMatches) {
     match submatches.subcommand() {
         Some(("add", submatches)) => {
             let project = grace::project::Project::open(PathBuf::from("."));
-            project.add_registry(submatches.get_one::<String>("uri").unwrap().clone());
+            let auth = Credentials {
+                ssh_key: submatches.get_one::<String>("ssh-key").cloned(),
+                askpass: submatches.get_one::<String>("askpass").cloned(),
+                token_env: submatches.get_one::<String>("token-env").cloned(),
+                username: submatches.get_one::<String>("username").cloned(),
+            };
+            if let Err(e) = project.add_registry(Registry {
+                uri: submatches.get_one::<String>("uri").unwrap().clone(),
+                branch: submatches.get_one::<String>("branch").cloned(),
+                auth: (auth != Credentials::default()).then_some(auth),
+            }) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
         }
 
         Some(("update", _)) => {
@@ -79,6 +274,51 @@ fn do_registry_command(submatches: &clap::ArgMatches) {
             project.update_registries();
         }
 
+        Some(("init", submatches)) => {
+            if let Err(e) =
+                grace::registry::init(PathBuf::from(submatches.get_one::<String>("path").unwrap()))
+            {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Some(("add-package", submatches)) => {
+            if let Err(e) = grace::registry::add_package(
+                PathBuf::from(submatches.get_one::<String>("path").unwrap()),
+                submatches.get_one::<String>("name").unwrap().clone(),
+                submatches.get_one::<String>("uri").unwrap().clone(),
+            ) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Some(("list", _)) => {
+            let project = grace::project::Project::open(PathBuf::from("."));
+            project.list_registries();
+        }
+
+        Some(("info", submatches)) => {
+            let project = grace::project::Project::open(PathBuf::from("."));
+            if let Err(e) =
+                project.registry_info(submatches.get_one::<String>("uri").unwrap().clone())
+            {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Some(("remove", submatches)) => {
+            let project = grace::project::Project::open(PathBuf::from("."));
+            if let Err(e) =
+                project.remove_registry(submatches.get_one::<String>("uri").unwrap().clone())
+            {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+
         _ => unreachable!(),
     }
 }